@@ -80,6 +80,42 @@ Item {
                 }
             }
 
+            Text {
+                Layout.alignment: Qt.AlignCenter
+                text: "or"
+                color: "white"
+            }
+
+            Rectangle {
+                Layout.alignment: Qt.AlignCenter
+
+                color: "white"
+                Layout.preferredWidth: 340
+                Layout.preferredHeight: 40
+                radius: 20
+
+                TextInput {
+                    id: token
+                    anchors.fill: parent
+                    anchors.margins: 10
+                    anchors.leftMargin: 20
+                    anchors.rightMargin: 20
+
+                    width: parent.implicitWidth
+
+                    property string placeholderText: "Access token..."
+
+                    echoMode: TextInput.Password
+
+                    Text {
+                        anchors.fill: parent
+                        text: parent.placeholderText
+                        color: "#222"
+                        visible: !parent.text
+                    }
+                }
+            }
+
             Rectangle {
                 Layout.alignment: Qt.AlignCenter
 
@@ -0,0 +1,48 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use librespot_core::{Error, FileId, Session};
+
+/// In-memory cache of the images fetched from Spotify's CDN, keyed by image id. The oldest
+/// images are dropped first once the capacity is reached.
+pub struct ArtworkCache {
+    capacity: usize,
+    images: HashMap<FileId, Vec<u8>>,
+    order: VecDeque<FileId>,
+}
+
+impl ArtworkCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            images: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+    pub fn get(&self, id: &FileId) -> Option<Vec<u8>> {
+        self.images.get(id).cloned()
+    }
+    pub fn insert(&mut self, id: FileId, data: Vec<u8>) {
+        if self.images.insert(id, data).is_none() {
+            self.order.push_back(id);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.images.remove(&oldest);
+            }
+        }
+    }
+}
+
+pub async fn fetch_artwork(
+    session: &Session,
+    cache: &Mutex<ArtworkCache>,
+    id: FileId,
+) -> Result<Vec<u8>, Error> {
+    if let Some(data) = cache.lock().unwrap().get(&id) {
+        return Ok(data);
+    }
+    let data = session.spclient().get_image(&id).await?.to_vec();
+    cache.lock().unwrap().insert(id, data.clone());
+    Ok(data)
+}
@@ -0,0 +1,64 @@
+use std::{error, fmt, io};
+
+/// Why the loader couldn't open, read or close a track.
+#[derive(Debug)]
+pub enum LoaderError {
+    /// The track isn't open, or was closed meanwhile.
+    NotOpen,
+    /// The track is open, but all its references were already dropped.
+    NotReferenced,
+    /// Spotify has no file of the track that can be played.
+    Unavailable(String),
+    /// Spotify has no such track.
+    NotFound(String),
+    /// The track is restricted in the user's market, and none of its alternatives is playable.
+    RegionLocked(String),
+    /// None of the track's files is in a format the plugin can serve.
+    NoSupportedFormat(String),
+    /// None of the track's files is in a format the client can decode.
+    UnsupportedFormat(String),
+    /// The session is offline and the track isn't in the offline cache.
+    NotOffline,
+    /// Spotify couldn't be reached for the track's metadata or audio file.
+    Fetch(librespot_core::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoaderError::NotOpen => f.write_str("No track is currently open"),
+            LoaderError::NotReferenced => f.write_str("track has no reference left to close"),
+            LoaderError::Unavailable(reason)
+            | LoaderError::NotFound(reason)
+            | LoaderError::RegionLocked(reason)
+            | LoaderError::NoSupportedFormat(reason)
+            | LoaderError::UnsupportedFormat(reason) => f.write_str(reason),
+            LoaderError::NotOffline => f.write_str("track is not available offline"),
+            LoaderError::Fetch(e) => write!(f, "unable to fetch track: {}", e),
+            LoaderError::Io(e) => write!(f, "unable to read track: {}", e),
+        }
+    }
+}
+
+impl error::Error for LoaderError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            LoaderError::Fetch(e) => Some(e),
+            LoaderError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<librespot_core::Error> for LoaderError {
+    fn from(e: librespot_core::Error) -> Self {
+        LoaderError::Fetch(e)
+    }
+}
+
+impl From<io::Error> for LoaderError {
+    fn from(e: io::Error) -> Self {
+        LoaderError::Io(e)
+    }
+}
@@ -1,82 +1,302 @@
-use std::io::Seek;
-use std::{collections::HashMap, io::SeekFrom};
+use std::io::{Read, Seek};
+use std::time::{Duration, Instant};
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    io::SeekFrom,
+    sync::Arc,
+};
 
-use futures_util::{future, stream::futures_unordered::FuturesUnordered, StreamExt};
+use futures_util::{
+    future,
+    stream::{self, futures_unordered::FuturesUnordered},
+    StreamExt,
+};
 
-use librespot_audio::{AudioDecrypt, AudioFile, StreamLoaderController};
-use librespot_core::{Session, SpotifyId};
-use librespot_metadata::audio::{AudioFileFormat, AudioFiles, AudioItem};
+use librespot_audio::{AudioDecrypt, AudioFile};
+use librespot_core::{
+    cache::Cache, error::ErrorKind, spotify_id::SpotifyItemType, FileId, Session, SpotifyId,
+};
+use librespot_metadata::audio::{AudioFileFormat, AudioFiles, AudioItem, UniqueFields};
+use librespot_metadata::{availability::UnavailabilityReason, Metadata, Track};
+use librespot_playback::config::Bitrate;
 use log::{debug, error, info, warn};
+use tokio::sync::{watch, Mutex, OwnedMutexGuard};
 
-use super::track::{OpenedTrack, Subfile};
+use super::error::LoaderError;
+use super::offline::OfflineIndex;
+use super::track::{Normalisation, OpenedTrack, Subfile};
+use crate::metadata;
 
 // Spotify inserts a custom Ogg packet at the start with custom metadata values, that you would
 // otherwise expect in Vorbis comments. This packet isn't well-formed and players may balk at it.
 const SPOTIFY_OGG_HEADER_END: u64 = 0xa7;
+// Offset of the normalisation values within that packet: track gain and peak, then album gain
+// and peak, as little-endian floats.
+const SPOTIFY_NORMALISATION_OFFSET: u64 = 144;
+
+// Delay before retrying to open an audio file, growing with each attempt.
+const OPEN_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Bounds on the audio keys requested ahead of time with `prewarm_keys`.
+const KEY_PREWARM_CONCURRENCY: usize = 4;
+
+// AAC comes last, for the tracks and accounts which are offered nothing else.
+const TRACK_FORMATS: [AudioFileFormat; 9] = [
+    AudioFileFormat::MP3_320,
+    AudioFileFormat::OGG_VORBIS_320,
+    AudioFileFormat::MP3_256,
+    AudioFileFormat::MP3_160,
+    AudioFileFormat::OGG_VORBIS_160,
+    AudioFileFormat::MP3_96,
+    AudioFileFormat::OGG_VORBIS_96,
+    AudioFileFormat::AAC_48,
+    AudioFileFormat::AAC_24,
+];
+
+// (Most) podcasts seem to support only 96 kbps Ogg Vorbis, so fall back to it, but some
+// shows offer higher quality Ogg streams that should be picked first when present.
+const EPISODE_FORMATS: [AudioFileFormat; 9] = [
+    AudioFileFormat::OGG_VORBIS_320,
+    AudioFileFormat::OGG_VORBIS_160,
+    AudioFileFormat::MP3_320,
+    AudioFileFormat::MP3_256,
+    AudioFileFormat::MP3_160,
+    AudioFileFormat::MP3_96,
+    AudioFileFormat::OGG_VORBIS_96,
+    AudioFileFormat::AAC_48,
+    AudioFileFormat::AAC_24,
+];
+
+/// Order in which the files of an audio item are considered, on top of the built-in one.
+#[derive(Clone, Debug, Default)]
+pub struct FormatPreference {
+    /// Formats tried first, in this order.
+    pub formats: Vec<AudioFileFormat>,
+    /// Formats above this bitrate are only used when no other one is available.
+    pub bitrate: Option<Bitrate>,
+    /// Try the lossless file first. It is never used otherwise.
+    pub lossless: bool,
+    /// Only formats the client can decode are considered, any format when empty.
+    pub accepted: Vec<AudioFileFormat>,
+}
+
+impl FormatPreference {
+    /// Same preference, with the overrides of a given open.
+    fn for_open(&self, options: &OpenOptions) -> Self {
+        Self {
+            accepted: options.accepted.clone(),
+            bitrate: options.bitrate.or(self.bitrate),
+            ..self.clone()
+        }
+    }
+    fn accepts(&self, format: AudioFileFormat) -> bool {
+        self.accepted.is_empty() || self.accepted.contains(&format)
+    }
+    fn candidates(
+        &self,
+        audio_item: &AudioItem,
+        format: Option<AudioFileFormat>,
+    ) -> Vec<AudioFileFormat> {
+        let max_data_rate = self.bitrate.map(|bitrate| {
+            TrackLoader::stream_data_rate(match bitrate {
+                Bitrate::Bitrate96 => AudioFileFormat::OGG_VORBIS_96,
+                Bitrate::Bitrate160 => AudioFileFormat::OGG_VORBIS_160,
+                Bitrate::Bitrate320 => AudioFileFormat::OGG_VORBIS_320,
+            })
+        });
+        let (within, above): (Vec<_>, Vec<_>) = preferred_formats(audio_item)
+            .iter()
+            .copied()
+            .partition(|format| {
+                max_data_rate.map_or(true, |max| TrackLoader::stream_data_rate(*format) <= max)
+            });
+        let lossless = Some(AudioFileFormat::FLAC_FLAC).filter(|_| self.lossless);
+        format
+            .into_iter()
+            .chain(self.formats.iter().copied())
+            .chain(lossless)
+            .chain(within)
+            .chain(above)
+            .filter(|format| self.accepts(*format))
+            .collect()
+    }
+}
+
+/// What a client asks for when opening a track, on top of the loader's format preference.
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions {
+    /// Format to serve, when the track offers it.
+    pub format: Option<AudioFileFormat>,
+    /// Formats the client can decode, any when empty.
+    pub accepted: Vec<AudioFileFormat>,
+    /// Bitrate to use instead of the configured one.
+    pub bitrate: Option<Bitrate>,
+}
 
 pub struct TrackLoader {
     session: Session,
     opened_tracks: HashMap<SpotifyId, OpenedTrack>,
+    /// Tracks being loaded by `open_shared`, which resolve once their load is over.
+    opening: HashMap<SpotifyId, watch::Receiver<()>>,
+    /// Tracks loaded ahead of their open, without any reference. Least recently used first.
+    preloaded: VecDeque<(SpotifyId, OpenedTrack)>,
+    max_preloaded: usize,
+    offline: Option<OfflineIndex>,
+    open_retries: u32,
+    fetch_buffer_size: usize,
+    prefer_cached: bool,
+    formats: FormatPreference,
+    market: Option<String>,
 }
 
 impl TrackLoader {
-    pub fn new(session: Session) -> Self {
+    pub fn new(session: Session, offline: Option<OfflineIndex>) -> Self {
         Self {
             session,
             opened_tracks: HashMap::new(),
+            opening: HashMap::new(),
+            preloaded: VecDeque::new(),
+            max_preloaded: 8,
+            offline,
+            open_retries: 0,
+            fetch_buffer_size: 10_240,
+            prefer_cached: false,
+            formats: FormatPreference::default(),
+            market: None,
+        }
+    }
+    pub fn with_open_retries(mut self, open_retries: u32) -> Self {
+        self.open_retries = open_retries;
+        self
+    }
+    /// Number of preloaded tracks kept until they're opened, `0` not to keep any.
+    pub fn with_max_preloaded(mut self, max_preloaded: usize) -> Self {
+        self.max_preloaded = max_preloaded;
+        self
+    }
+    pub fn with_fetch_buffer_size(mut self, fetch_buffer_size: usize) -> Self {
+        self.fetch_buffer_size = fetch_buffer_size;
+        self
+    }
+    /// Among the alternatives of an unavailable track, prefer one already in the cache.
+    pub fn with_prefer_cached(mut self, prefer_cached: bool) -> Self {
+        self.prefer_cached = prefer_cached;
+        self
+    }
+    pub fn with_format_preference(mut self, formats: FormatPreference) -> Self {
+        self.formats = formats;
+        self
+    }
+    /// Check the availability of the tracks in `market` rather than in the account's country.
+    pub fn with_market(mut self, market: Option<String>) -> Self {
+        self.market = market;
+        self
+    }
+    /// A copy of the loader without any track, to load one without locking the shared loader.
+    fn detached(&self) -> Self {
+        Self {
+            session: self.session.clone(),
+            opened_tracks: HashMap::new(),
+            opening: HashMap::new(),
+            preloaded: VecDeque::new(),
+            max_preloaded: self.max_preloaded,
+            offline: self.offline.clone(),
+            open_retries: self.open_retries,
+            fetch_buffer_size: self.fetch_buffer_size,
+            prefer_cached: self.prefer_cached,
+            formats: self.formats.clone(),
+            market: self.market.clone(),
         }
     }
+    /// Load the tracks from another session from now on, e.g. after a reconnection. Opened
+    /// tracks are kept, and get reloaded from it when their fetch fails.
+    pub fn set_session(&mut self, session: Session) {
+        self.session = session;
+    }
+    fn is_online(&self) -> bool {
+        !self.session.username().is_empty() && !self.session.is_invalid()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.opened_tracks.is_empty()
+    }
+    pub fn opened(&self) -> impl Iterator<Item = (&SpotifyId, &OpenedTrack)> {
+        self.opened_tracks.iter()
+    }
     pub fn get_opened(&self, track: &SpotifyId) -> Option<&OpenedTrack> {
         self.opened_tracks.get(track)
     }
     pub fn get_opened_mut(&mut self, track: &SpotifyId) -> Option<&mut OpenedTrack> {
         self.opened_tracks.get_mut(track)
     }
-    pub fn close(&mut self, track: &SpotifyId) -> Result<(), String> {
-        if let Some(loaded_track) = self.get_opened(track) {
-            if loaded_track.decr_ref() <= 1 {
-                self.opened_tracks
-                    .remove(track)
-                    .ok_or("Cannot close opened track".to_owned())?;
-            }
-            Ok(())
-        } else {
-            Err("No track is currently open".to_string())
+    /// Drop a reference on an opened track, which is only closed once no reference is left.
+    pub fn close(&mut self, track: &SpotifyId) -> Result<(), LoaderError> {
+        let loaded_track = self.get_opened(track).ok_or(LoaderError::NotOpen)?;
+        let ref_count = loaded_track.decr_ref().ok_or(LoaderError::NotReferenced)?;
+        if ref_count > 0 {
+            debug!(
+                "Keeping <{}> open, {} references left",
+                loaded_track.name(),
+                ref_count
+            );
+            return Ok(());
+        }
+        info!("Closing <{}>", loaded_track.name());
+        if let Some(closed) = self.opened_tracks.remove(track) {
+            closed.stop_fetching();
         }
+        Ok(())
     }
-    pub fn seek(&mut self, track: &SpotifyId, position: u64) -> Result<u64, String> {
-        if let Some(loaded_track) = self.get_opened_mut(track) {
-            loaded_track
-                .seek(SeekFrom::Start(position))
-                .map_err(|e| e.to_string())
-        } else {
-            Err("No track is currently open".to_owned())
+    /// Close every opened track, whatever its references. Returns the number of tracks closed.
+    pub fn close_all(&mut self) -> usize {
+        let count = self.opened_tracks.len();
+        for (_, closed) in self.opened_tracks.drain() {
+            closed.stop_fetching();
         }
+        for (_, preloaded) in self.preloaded.drain(..) {
+            preloaded.stop_fetching();
+        }
+        count
     }
-    async fn find_available_alternative(&self, audio_item: AudioItem) -> Option<AudioItem> {
-        if let Err(e) = audio_item.availability {
-            error!("Track is unavailable: {}", e);
-            None
-        } else if !audio_item.files.is_empty() {
-            Some(audio_item)
-        } else if let Some(alternatives) = &audio_item.alternatives {
-            let alternatives: FuturesUnordered<_> = alternatives
-                .iter()
-                .map(|alt_id| AudioItem::get_file(&self.session, *alt_id))
-                .collect();
-
-            alternatives
-                .filter_map(|x| future::ready(x.ok()))
-                .filter(|x| future::ready(x.availability.is_ok()))
-                .next()
-                .await
+    fn is_preloaded(&self, track: &SpotifyId) -> bool {
+        self.preloaded.iter().any(|(id, _)| id == track)
+    }
+    /// Take a preloaded track out, if it was loaded in a way that fits `options`. It is dropped
+    /// otherwise, as the track gets loaded again.
+    fn take_preloaded(&mut self, track: &SpotifyId, options: &OpenOptions) -> Option<OpenedTrack> {
+        let index = self.preloaded.iter().position(|(id, _)| id == track)?;
+        let (_, preloaded) = self.preloaded.remove(index)?;
+        let accepted = &options.accepted;
+        // The format served fits as well as the one ideally requested, which the track may not
+        // offer.
+        let fits = options.format.map_or(true, |format| {
+            format == preloaded.requested_format() || format == preloaded.format()
+        }) && (accepted.is_empty() || accepted.contains(&preloaded.format()));
+        if fits {
+            Some(preloaded)
         } else {
-            error!("Track should be available, but no alternatives found.");
+            debug!("Dropping the preload of <{}>", preloaded.name());
+            preloaded.stop_fetching();
             None
         }
     }
-
-    fn stream_data_rate(format: AudioFileFormat) -> usize {
+    pub fn seek(&mut self, track: &SpotifyId, position: u64) -> Result<u64, LoaderError> {
+        let loaded_track = self.get_opened_mut(track).ok_or(LoaderError::NotOpen)?;
+        debug!("Seeking <{}> to {}", loaded_track.name(), position);
+        let position = loaded_track.seek(SeekFrom::Start(position))?;
+        loaded_track.prefetch();
+        Ok(position)
+    }
+    pub fn seek_time(&mut self, track: &SpotifyId, position_ms: u32) -> Result<u64, LoaderError> {
+        let loaded_track = self.get_opened_mut(track).ok_or(LoaderError::NotOpen)?;
+        debug!("Seeking <{}> to {}ms", loaded_track.name(), position_ms);
+        let position = loaded_track.seek_time(position_ms)?;
+        loaded_track.prefetch();
+        Ok(position)
+    }
+    pub fn offline(&self) -> Option<&OfflineIndex> {
+        self.offline.as_ref()
+    }
+    pub(super) fn stream_data_rate(format: AudioFileFormat) -> usize {
         let kbps = match format {
             AudioFileFormat::OGG_VORBIS_96 => 12,
             AudioFileFormat::OGG_VORBIS_160 => 20,
@@ -93,28 +313,118 @@ impl TrackLoader {
         kbps * 1024
     }
 
+    /// Load a track whose encrypted file and key are both available locally, without any
+    /// request to Spotify. If given, `format` is the only one the file may be in.
+    fn load_offline(
+        &self,
+        spotify_id: SpotifyId,
+        formats: &FormatPreference,
+        format: Option<AudioFileFormat>,
+    ) -> Option<OpenedTrack> {
+        let entry = self
+            .offline
+            .as_ref()?
+            .get(&spotify_id)
+            .filter(|entry| formats.accepts(entry.format))
+            .filter(|entry| format.map_or(true, |format| format == entry.format))?;
+        let file = self.session.cache()?.file(entry.file_id)?;
+
+        let encrypted_file = AudioFile::Cached(file);
+        let stream_loader_controller = encrypted_file.get_stream_loader_controller().ok()?;
+        let mut decrypted_file = AudioDecrypt::new(Some(entry.key), encrypted_file);
+        if !has_valid_magic(&mut decrypted_file, entry.format) {
+            warn!("Cached file {} is corrupt, removing it", entry.file_id);
+            if let Err(e) = self.session.cache()?.remove_file(entry.file_id) {
+                warn!("Unable to remove {} from the cache: {}", entry.file_id, e);
+            }
+            return None;
+        }
+
+        let (offset, normalisation) = if AudioFiles::is_ogg_vorbis(entry.format) {
+            (
+                SPOTIFY_OGG_HEADER_END,
+                read_normalisation(&mut decrypted_file),
+            )
+        } else {
+            (0, None)
+        };
+        let audio_file = match Subfile::new(
+            decrypted_file,
+            offset,
+            stream_loader_controller.len() as u64,
+        ) {
+            Ok(audio_file) => audio_file,
+            Err(e) => {
+                error!("Unable to open offline subfile: {}", e);
+                return None;
+            }
+        };
+
+        info!("<{}> loaded from the offline cache", spotify_id);
+        Some(
+            OpenedTrack::new(
+                Box::new(audio_file),
+                stream_loader_controller,
+                entry.format,
+                true,
+            )
+            .with_header_len(offset)
+            .with_normalisation(normalisation)
+            .with_name(spotify_id.to_string()),
+        )
+    }
+
     async fn load_track(
         &self,
         spotify_id: SpotifyId,
-    ) -> Option<(
-        Subfile<AudioDecrypt<AudioFile>>,
-        AudioFileFormat,
-        StreamLoaderController,
-    )> {
-        let audio_item = match AudioItem::get_file(&self.session, spotify_id).await {
-            Ok(audio) => match self.find_available_alternative(audio).await {
-                Some(audio) => audio,
-                None => {
-                    warn!(
-                        "<{}> is not available",
-                        spotify_id.to_uri().unwrap_or_default()
-                    );
-                    return None;
+        options: &OpenOptions,
+    ) -> Result<OpenedTrack, LoaderError> {
+        let (format, accepted) = (options.format, &options.accepted);
+        let formats = self.formats.for_open(options);
+        if !self.is_online() {
+            return self
+                .load_offline(spotify_id, &formats, None)
+                .ok_or(LoaderError::NotOffline);
+        }
+        // A track played before is served from its cached file right away, rather than after
+        // resolving its metadata and key with Spotify again.
+        if let Some(opened) = self.load_offline(spotify_id, &formats, format) {
+            return Ok(opened);
+        }
+
+        let market = self.market.as_deref();
+        let audio_item = match get_file(&self.session, spotify_id, market).await {
+            Ok(audio) => {
+                match find_available_alternative(
+                    &self.session,
+                    audio,
+                    self.prefer_cached,
+                    &formats,
+                    market,
+                )
+                .await
+                {
+                    Ok(audio) => audio,
+                    Err(e) => {
+                        warn!(
+                            "<{}> is not available",
+                            spotify_id.to_uri().unwrap_or_default()
+                        );
+                        return Err(e);
+                    }
                 }
-            },
+            }
             Err(e) => {
                 error!("Unable to load audio item: {:?}", e);
-                return None;
+                return self
+                    .load_offline(spotify_id, &formats, None)
+                    .ok_or_else(|| match e.kind {
+                        ErrorKind::NotFound => LoaderError::NotFound(format!(
+                            "{} does not exist",
+                            spotify_id.to_uri().unwrap_or_default()
+                        )),
+                        _ => LoaderError::Fetch(e),
+                    });
             }
         };
 
@@ -123,35 +433,39 @@ impl TrackLoader {
             audio_item.name, audio_item.uri
         );
 
-        // (Most) podcasts seem to support only 96 kbps Ogg Vorbis, so fall back to it
-        let formats = [
-            AudioFileFormat::MP3_320,
-            AudioFileFormat::OGG_VORBIS_320,
-            AudioFileFormat::MP3_256,
-            AudioFileFormat::MP3_160,
-            AudioFileFormat::OGG_VORBIS_160,
-            AudioFileFormat::MP3_96,
-            AudioFileFormat::OGG_VORBIS_96,
-        ];
-
         debug!("Available audio file: {:?}", audio_item.files);
 
-        let (format, file_id) =
-            match formats
-                .iter()
-                .find_map(|format| match audio_item.files.get(format) {
-                    Some(&file_id) => Some((*format, file_id)),
-                    _ => None,
-                }) {
-                Some(t) => t,
-                None => {
-                    warn!(
-                        "<{}> is not available in any supported format",
-                        audio_item.name
-                    );
-                    return None;
-                }
-            };
+        // Checked before anything gets fetched, not to download a file the client can't use.
+        let (selected_format, file_id) = match select_file(&audio_item, &formats, format) {
+            Some(t) => t,
+            None if !accepted.is_empty() => {
+                warn!(
+                    "<{}> isn't available in any format the client supports",
+                    audio_item.name
+                );
+                return Err(LoaderError::UnsupportedFormat(format!(
+                    "{} is not available in any format the client supports",
+                    audio_item.name
+                )));
+            }
+            None => {
+                warn!(
+                    "<{}> is not available in any supported format",
+                    audio_item.name
+                );
+                return Err(LoaderError::NoSupportedFormat(format!(
+                    "{} is not available in any supported format",
+                    audio_item.name
+                )));
+            }
+        };
+        let requested_format = formats
+            .candidates(&audio_item, format)
+            .first()
+            .copied()
+            .unwrap_or(selected_format);
+        let format = selected_format;
+        info!("Serving <{}> as {:?}", audio_item.name, format);
 
         let bytes_per_second = Self::stream_data_rate(format);
         info!(
@@ -159,36 +473,99 @@ impl TrackLoader {
             bytes_per_second, file_id
         );
 
-        // // This is only a loop to be able to reload the file if an error occurred
-        // // while opening a cached file.
-        // loop {
-        let encrypted_file = AudioFile::open(&self.session, file_id, 10240);
-
-        let encrypted_file = match encrypted_file.await {
-            Ok(encrypted_file) => encrypted_file,
-            Err(e) => {
-                error!("Unable to load encrypted file: {:?}", e);
-                return None;
-            }
-        };
-
-        let stream_loader_controller = encrypted_file.get_stream_loader_controller().ok()?;
-
         // Not all audio files are encrypted. If we can't get a key, try loading the track
         // without decryption. If the file was encrypted after all, the decoder will fail
         // parsing and bail out, so we should be safe from outputting ear-piercing noise.
-        let key = match self.session.audio_key().request(spotify_id, file_id).await {
-            Ok(key) => Some(key),
+        let prewarmed = self
+            .offline
+            .as_ref()
+            .and_then(|offline| offline.get(&spotify_id))
+            .filter(|entry| entry.file_id == file_id);
+        let key = match prewarmed {
+            Some(entry) => {
+                debug!("Using the stored audio key of {}", spotify_id);
+                Ok(entry.key)
+            }
+            None => self.session.audio_key().request(spotify_id, file_id).await,
+        };
+        let key = match key {
+            Ok(key) => {
+                if let Some(offline) = &self.offline {
+                    offline.save(&spotify_id, file_id, format, &key);
+                }
+                Some(key)
+            }
             Err(e) => {
                 warn!("Unable to load key, continuing without decryption: {}", e);
                 None
             }
         };
-        let decrypted_file = AudioDecrypt::new(key, encrypted_file);
+
+        // A truncated or corrupt cached file decrypts to garbage, in which case drop it and
+        // fetch the file again from the network.
+        let mut healed = false;
+        let (mut decrypted_file, stream_loader_controller, cached) = loop {
+            let mut attempt = 0;
+            let encrypted_file = loop {
+                let from_cache = self
+                    .session
+                    .cache()
+                    .is_some_and(|cache| cache.file(file_id).is_some());
+                let e = match AudioFile::open(&self.session, file_id, self.fetch_buffer_size).await
+                {
+                    Ok(encrypted_file) => break encrypted_file,
+                    Err(e) => e,
+                };
+                // The cached copy may be what's failing, so it always gets one more try from
+                // the network, even when retries are disabled.
+                let discard_cached = from_cache && !healed;
+                if attempt >= self.open_retries && !discard_cached {
+                    error!("Unable to load encrypted file: {:?}", e);
+                    return Err(LoaderError::Fetch(e));
+                }
+                attempt += 1;
+                warn!("Unable to load encrypted file (attempt {}): {}", attempt, e);
+                if from_cache {
+                    warn!(
+                        "Discarding cached file {}, fetching it from the network",
+                        file_id
+                    );
+                    if let Some(cache) = self.session.cache() {
+                        if let Err(e) = cache.remove_file(file_id) {
+                            warn!("Unable to remove {} from the cache: {}", file_id, e);
+                        }
+                    }
+                    healed = true;
+                }
+                tokio::time::sleep(OPEN_RETRY_DELAY * attempt).await;
+            };
+
+            let cached = encrypted_file.is_cached();
+            let stream_loader_controller = encrypted_file.get_stream_loader_controller()?;
+            let mut decrypted_file = AudioDecrypt::new(key, encrypted_file);
+
+            if cached && key.is_some() && !healed && !has_valid_magic(&mut decrypted_file, format) {
+                warn!("Cached file {} is corrupt, fetching it again", file_id);
+                if let Some(cache) = self.session.cache() {
+                    if let Err(e) = cache.remove_file(file_id) {
+                        warn!("Unable to remove {} from the cache: {}", file_id, e);
+                    }
+                }
+                healed = true;
+                continue;
+            }
+            break (decrypted_file, stream_loader_controller, cached);
+        };
 
         let is_ogg_vorbis = AudioFiles::is_ogg_vorbis(format);
+        // Spotify stores normalisation data in its custom packet, which only makes sense
+        // once decrypted. Other formats come without any.
+        let normalisation = if is_ogg_vorbis && key.is_some() {
+            read_normalisation(&mut decrypted_file)
+        } else {
+            None
+        };
         let offset = if is_ogg_vorbis {
-            // Spotify stores normalisation data in a custom
             SPOTIFY_OGG_HEADER_END
         } else {
             0
@@ -201,7 +578,7 @@ impl TrackLoader {
             Ok(audio_file) => audio_file,
             Err(e) => {
                 error!("PlayerTrackLoader::load_track error opening subfile: {}", e);
-                return None;
+                return Err(LoaderError::Io(e));
             }
         };
 
@@ -211,30 +588,602 @@ impl TrackLoader {
             stream_loader_controller.len()
         );
 
+        // Only fetch the regions being read, so a seek doesn't wait on the file being
+        // downloaded from its start.
         stream_loader_controller.set_random_access_mode();
-        // stream_loader_controller.set_stream_mode();
 
-        // TODO use a buffer instead of full read
-        stream_loader_controller.range_to_end_available();
-        // stream_loader_controller.fetch(Range { start: 0, length: stream_loader_controller.len() });
+        Ok(OpenedTrack::new(
+            Box::new(audio_file),
+            stream_loader_controller,
+            format,
+            cached,
+        )
+        .with_header_len(offset)
+        .with_requested_format(requested_format)
+        .with_normalisation(normalisation)
+        .with_name(audio_item.name.clone()))
+    }
 
-        Some((audio_file, format, stream_loader_controller))
-        // }
+    /// Restart the fetch of an already opened track from the current session, keeping its
+    /// references. Used to recover a read after the session got reconnected.
+    pub async fn reload(&mut self, track: SpotifyId) -> Result<(), LoaderError> {
+        let Some(opened) = self.opened_tracks.get(&track) else {
+            return Err(LoaderError::NotOpen);
+        };
+        let reloaded = self
+            .load_track(
+                track,
+                &OpenOptions {
+                    format: Some(opened.requested_format()),
+                    ..OpenOptions::default()
+                },
+            )
+            .await?;
+        self.opened_tracks
+            .get_mut(&track)
+            .ok_or(LoaderError::NotOpen)?
+            .rebind(reloaded);
+        Ok(())
     }
 
-    pub async fn open(&mut self, track: SpotifyId) -> Result<(i64, AudioFileFormat), String> {
-        if let Some(loaded_track) = self.opened_tracks.get(&track) {
-            loaded_track.incr_ref();
-            return Ok((loaded_track.len() as i64, loaded_track.format()));
+    /// Open a placeholder in place of `track`, or take another reference on what is already
+    /// open for it.
+    pub fn open_placeholder(&mut self, track: SpotifyId, duration_ms: u32) -> &mut OpenedTrack {
+        match self.opened_tracks.entry(track) {
+            Entry::Occupied(entry) => {
+                let opened = entry.into_mut();
+                let ref_count = opened.incr_ref();
+                debug!("<{}> now has {} references", opened.name(), ref_count);
+                opened
+            }
+            Entry::Vacant(entry) => entry.insert(
+                OpenedTrack::placeholder(duration_ms).with_name(format!("{} (placeholder)", track)),
+            ),
+        }
+    }
+
+    /// Open a track, or take another reference on it if already open, in which case the
+    /// requested format and bitrate are ignored and the track keeps being served in its current
+    /// format, as long as the client accepts it.
+    pub async fn open(
+        &mut self,
+        track: SpotifyId,
+        options: &OpenOptions,
+    ) -> Result<&mut OpenedTrack, LoaderError> {
+        if self.opened_tracks.contains_key(&track) {
+            let loaded_track = self
+                .opened_tracks
+                .get_mut(&track)
+                .ok_or(LoaderError::NotOpen)?;
+            let accepted = &options.accepted;
+            if !accepted.is_empty() && !accepted.contains(&loaded_track.format()) {
+                return Err(LoaderError::UnsupportedFormat(format!(
+                    "{} is already open as {:?}, which the client doesn't support",
+                    loaded_track.name(),
+                    loaded_track.format()
+                )));
+            }
+            let ref_count = loaded_track.incr_ref();
+            debug!("<{}> now has {} references", loaded_track.name(), ref_count);
+            return Ok(loaded_track);
+        }
+
+        let loaded_track = match self.take_preloaded(&track, options) {
+            Some(preloaded) => {
+                debug!("Opening <{}> from its preload", preloaded.name());
+                preloaded
+            }
+            None => self.load_timed(track, options).await?,
+        };
+        Ok(self.opened_tracks.entry(track).or_insert(loaded_track))
+    }
+
+    /// Open a track in the shared `loader`, which is only locked to look the track up and to
+    /// store it once loaded, not while it's being fetched. Concurrent opens of the same track
+    /// wait for the first one and take another reference on it, or load it themselves if it
+    /// failed. The returned guard holds the loader with the track open.
+    pub async fn open_shared(
+        loader: &Arc<Mutex<TrackLoader>>,
+        track: SpotifyId,
+        options: &OpenOptions,
+    ) -> Result<OwnedMutexGuard<TrackLoader>, LoaderError> {
+        // Dropped once the load is over, or abandoned, which wakes up the opens waiting on it.
+        let (detached, _done) = loop {
+            let mut guard = Arc::clone(loader).lock_owned().await;
+            if guard.opened_tracks.contains_key(&track) || guard.is_preloaded(&track) {
+                guard.open(track, options).await?;
+                return Ok(guard);
+            }
+            // The load of an abandoned open, e.g. on timeout, is over without a result.
+            match guard
+                .opening
+                .get(&track)
+                .filter(|pending| pending.has_changed().is_ok())
+            {
+                Some(pending) => {
+                    let mut pending = pending.clone();
+                    drop(guard);
+                    debug!("Waiting for {} to be opened", track);
+                    let _ = pending.changed().await;
+                }
+                None => {
+                    let (done, pending) = watch::channel(());
+                    guard.opening.insert(track, pending);
+                    break (guard.detached(), done);
+                }
+            }
+        };
+
+        let loaded_track = detached.load_timed(track, options).await;
+        let mut guard = Arc::clone(loader).lock_owned().await;
+        guard.opening.remove(&track);
+        let loaded_track = loaded_track?;
+        match guard.opened_tracks.entry(track) {
+            // A placeholder got opened meanwhile.
+            Entry::Occupied(entry) => {
+                let ref_count = entry.get().incr_ref();
+                debug!("<{}> now has {} references", entry.get().name(), ref_count);
+                loaded_track.stop_fetching();
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(loaded_track);
+            }
+        }
+        Ok(guard)
+    }
+
+    /// Serve an opened track decoded, as done by `OpenedTrack::decode_wav`. Decoding reads the
+    /// file, which may still be downloading, so the track is taken out of the loader meanwhile
+    /// and the loader is unlocked, opens of the track waiting for it to be put back. On failure,
+    /// the track is closed.
+    pub async fn decode_shared(
+        loader: &Arc<Mutex<TrackLoader>>,
+        mut guard: OwnedMutexGuard<TrackLoader>,
+        track: SpotifyId,
+        duration_ms: u32,
+    ) -> Result<OwnedMutexGuard<TrackLoader>, LoaderError> {
+        let mut opened = guard
+            .opened_tracks
+            .remove(&track)
+            .ok_or(LoaderError::NotOpen)?;
+        let (_done, pending) = watch::channel(());
+        guard.opening.insert(track, pending);
+        drop(guard);
+
+        let decoding = tokio::task::spawn_blocking(move || {
+            let result = opened.decode_wav(duration_ms);
+            (opened, result)
+        })
+        .await;
+        let mut guard = Arc::clone(loader).lock_owned().await;
+        guard.opening.remove(&track);
+        let decoded = match decoding {
+            Ok((decoded, Ok(()))) => decoded,
+            Ok((failed, Err(e))) => {
+                info!("Closing <{}>, which couldn't be decoded", failed.name());
+                failed.stop_fetching();
+                return Err(e.into());
+            }
+            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::Other, e).into()),
+        };
+        match guard.opened_tracks.entry(track) {
+            // A placeholder got opened meanwhile.
+            Entry::Occupied(entry) => {
+                for _ in 0..decoded.ref_count() {
+                    entry.get().incr_ref();
+                }
+                decoded.stop_fetching();
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(decoded);
+            }
+        }
+        Ok(guard)
+    }
+
+    /// Load a track in the shared `loader` ahead of its open, which then takes it over. Nothing
+    /// is done if the track is already open, preloaded or being opened. The preload takes no
+    /// reference on the track, which is dropped if too many others are preloaded after it. A
+    /// track preloaded again counts as the most recently preloaded.
+    /// Returns whether the track was actually loaded.
+    pub async fn preload_shared(
+        loader: &Arc<Mutex<TrackLoader>>,
+        track: SpotifyId,
+        options: &OpenOptions,
+    ) -> Result<bool, LoaderError> {
+        let (detached, _done) = {
+            let mut guard = loader.lock().await;
+            if let Some(index) = guard.preloaded.iter().position(|(id, _)| *id == track) {
+                if let Some(preloaded) = guard.preloaded.remove(index) {
+                    guard.preloaded.push_back(preloaded);
+                }
+                return Ok(false);
+            }
+            if guard.opened_tracks.contains_key(&track)
+                || guard
+                    .opening
+                    .get(&track)
+                    .is_some_and(|pending| pending.has_changed().is_ok())
+            {
+                return Ok(false);
+            }
+            let (done, pending) = watch::channel(());
+            guard.opening.insert(track, pending);
+            (guard.detached(), done)
+        };
+
+        let loaded_track = detached.load_timed(track, options).await;
+        let mut guard = loader.lock().await;
+        guard.opening.remove(&track);
+        let loaded_track = loaded_track?;
+        // A placeholder got opened meanwhile.
+        if guard.opened_tracks.contains_key(&track) {
+            loaded_track.stop_fetching();
+            return Ok(false);
+        }
+        guard.preloaded.push_back((track, loaded_track));
+        while guard.preloaded.len() > guard.max_preloaded {
+            if let Some((_, evicted)) = guard.preloaded.pop_front() {
+                debug!("Dropping the preload of <{}>", evicted.name());
+                evicted.stop_fetching();
+            }
         }
+        Ok(true)
+    }
+
+    async fn load_timed(
+        &self,
+        track: SpotifyId,
+        options: &OpenOptions,
+    ) -> Result<OpenedTrack, LoaderError> {
+        let started = Instant::now();
+        let loaded_track = self.load_track(track, options).await?;
+        let load_time = started.elapsed();
+        info!(
+            "<{}> loaded in {:?} from {}",
+            loaded_track.name(),
+            load_time,
+            if loaded_track.is_cached() {
+                "the cache"
+            } else {
+                "the network"
+            }
+        );
+        Ok(loaded_track.with_load_time(load_time))
+    }
+}
 
-        if let Some((file, format, controller)) = self.load_track(track).await {
-            let filesize = controller.len();
-            self.opened_tracks
-                .insert(track, OpenedTrack::new(Box::new(file), controller, format));
-            Ok((filesize as i64, format))
+/// Fetch the audio item of `id`, available as per `market` rather than the account's country
+/// when given.
+async fn get_file(
+    session: &Session,
+    id: SpotifyId,
+    market: Option<&str>,
+) -> Result<AudioItem, librespot_core::Error> {
+    let mut audio_item = AudioItem::get_file(session, id).await?;
+    let Some(market) = market else {
+        return Ok(audio_item);
+    };
+    // An embargoed track isn't released in any market yet.
+    if id.item_type == SpotifyItemType::Track
+        && !matches!(audio_item.availability, Err(UnavailabilityReason::Embargo))
+    {
+        let track = Track::get(session, &id).await?;
+        audio_item.availability = if metadata::available_in(&track.restrictions, market) {
+            Ok(())
         } else {
-            Err("unable to load track".to_owned())
+            Err(UnavailabilityReason::Blocked)
+        };
+    }
+    Ok(audio_item)
+}
+
+async fn find_available_alternative(
+    session: &Session,
+    audio_item: AudioItem,
+    prefer_cached: bool,
+    formats: &FormatPreference,
+    market: Option<&str>,
+) -> Result<AudioItem, LoaderError> {
+    if audio_item.availability.is_ok() && !audio_item.files.is_empty() {
+        Ok(audio_item)
+    } else if let Some(alternatives) = audio_item
+        .alternatives
+        .as_ref()
+        .filter(|alternatives| !alternatives.is_empty())
+    {
+        // Tracks unavailable in the market are relinked to one of their alternatives.
+        let alternatives: FuturesUnordered<_> = alternatives
+            .iter()
+            .map(|alt_id| get_file(session, *alt_id, market))
+            .collect();
+
+        let available = alternatives
+            .filter_map(|x| future::ready(x.ok()))
+            .filter(|x| future::ready(x.availability.is_ok()));
+        if !prefer_cached {
+            return available.next().await.ok_or_else(|| {
+                LoaderError::RegionLocked(format!(
+                    "{} is not available in your market, nor any of its alternatives",
+                    audio_item.name
+                ))
+            });
+        }
+
+        // Wait for all the alternatives, to reuse one that was already downloaded.
+        let mut available: Vec<AudioItem> = available.collect().await;
+        match available
+            .iter()
+            .position(|alternative| is_cached(session, alternative, formats))
+        {
+            Some(index) => {
+                debug!("Using cached alternative {}", available[index].uri);
+                Ok(available.swap_remove(index))
+            }
+            None => available.into_iter().next().ok_or_else(|| {
+                LoaderError::RegionLocked(format!(
+                    "{} is not available in your market, nor any of its alternatives",
+                    audio_item.name
+                ))
+            }),
         }
+    } else if let Err(e) = &audio_item.availability {
+        error!("Track is unavailable: {}", e);
+        Err(LoaderError::RegionLocked(format!(
+            "{} is not available in your market: {}",
+            audio_item.name, e
+        )))
+    } else {
+        error!("Track should be available, but no alternatives found.");
+        Err(LoaderError::Unavailable(format!(
+            "no playable files for this track: {}",
+            audio_item.name
+        )))
+    }
+}
+
+/// Whether the file that would be played for this item is in the audio cache.
+fn is_cached(session: &Session, audio_item: &AudioItem, formats: &FormatPreference) -> bool {
+    match (session.cache(), select_file(audio_item, formats, None)) {
+        (Some(cache), Some((_, file_id))) => cache.file(file_id).is_some(),
+        _ => false,
+    }
+}
+
+fn preferred_formats(audio_item: &AudioItem) -> &'static [AudioFileFormat] {
+    match audio_item.unique_fields {
+        UniqueFields::Episode { .. } => &EPISODE_FORMATS,
+        UniqueFields::Track { .. } => &TRACK_FORMATS,
+    }
+}
+
+/// Pick the file to play among those available, in order of preference, trying `format`
+/// first when given.
+fn select_file(
+    audio_item: &AudioItem,
+    formats: &FormatPreference,
+    format: Option<AudioFileFormat>,
+) -> Option<(AudioFileFormat, FileId)> {
+    formats
+        .candidates(audio_item, format)
+        .into_iter()
+        .find_map(|format| Some((format, *audio_item.files.get(&format)?)))
+}
+
+/// librespot has no such helper for AAC, unlike the other formats.
+fn is_aac(format: AudioFileFormat) -> bool {
+    matches!(format, AudioFileFormat::AAC_24 | AudioFileFormat::AAC_48)
+}
+
+/// Parse the name of a supported format, as reported in `OpenResponse.format`.
+pub fn parse_format(name: &str) -> Option<AudioFileFormat> {
+    TRACK_FORMATS
+        .into_iter()
+        .chain([AudioFileFormat::FLAC_FLAC])
+        .find(|format| format!("{:?}", format).eq_ignore_ascii_case(name))
+}
+
+/// Check that `session` can play tracks, by resolving the audio file and key of `track`.
+pub async fn check_playback(session: &Session, track: SpotifyId) -> Result<(), LoaderError> {
+    let audio_item = AudioItem::get_file(session, track).await?;
+    let file_id = audio_item.files.values().next().ok_or_else(|| {
+        LoaderError::Unavailable(format!("{} has no audio file", audio_item.name))
+    })?;
+    session.audio_key().request(track, *file_id).await?;
+    Ok(())
+}
+
+/// Request the audio keys of `tracks` ahead of their opening and store them in the offline
+/// index, so `open` can skip that round trip. Returns how many tracks have a key available.
+pub async fn prewarm_keys(
+    session: &Session,
+    offline: &OfflineIndex,
+    tracks: &[SpotifyId],
+    prefer_cached: bool,
+    formats: &FormatPreference,
+    market: Option<&str>,
+) -> usize {
+    stream::iter(tracks)
+        .map(|&track| prewarm_key(session, offline, track, prefer_cached, formats, market))
+        .buffer_unordered(KEY_PREWARM_CONCURRENCY)
+        .filter(|prewarmed| future::ready(*prewarmed))
+        .count()
+        .await
+}
+
+async fn prewarm_key(
+    session: &Session,
+    offline: &OfflineIndex,
+    track: SpotifyId,
+    prefer_cached: bool,
+    formats: &FormatPreference,
+    market: Option<&str>,
+) -> bool {
+    if offline.get(&track).is_some() {
+        return true;
+    }
+    let audio_item = match get_file(session, track, market).await {
+        Ok(audio_item) => audio_item,
+        Err(e) => {
+            debug!("Cannot prewarm the key of {}: {}", track, e);
+            return false;
+        }
+    };
+    let audio_item =
+        match find_available_alternative(session, audio_item, prefer_cached, formats, market).await
+        {
+            Ok(audio_item) => audio_item,
+            Err(e) => {
+                debug!("Cannot prewarm the key of {}: {}", track, e);
+                return false;
+            }
+        };
+    let Some((format, file_id)) = select_file(&audio_item, formats, None) else {
+        return false;
+    };
+    match session.audio_key().request(track, file_id).await {
+        Ok(key) => {
+            offline.save(&track, file_id, format, &key);
+            true
+        }
+        Err(e) => {
+            debug!("Cannot prewarm the key of {}: {}", track, e);
+            false
+        }
+    }
+}
+
+/// Check the cached files of the offline entries, removing those whose content doesn't match
+/// their format, as well as the entries whose file is gone. Returns the number of files removed
+/// and the bytes freed.
+pub fn verify_offline(cache: &Cache, offline: &OfflineIndex) -> (u32, u64) {
+    let (mut removed, mut freed) = (0, 0);
+    for track in offline.tracks() {
+        let Some(entry) = offline.get(&track) else {
+            offline.remove(&track);
+            continue;
+        };
+        let Some(file) = cache.file(entry.file_id) else {
+            debug!("Removing offline entry of {}, its file isn't cached", track);
+            offline.remove(&track);
+            continue;
+        };
+        let size = file.metadata().map_or(0, |metadata| metadata.len());
+        let mut decrypted_file = AudioDecrypt::new(Some(entry.key), AudioFile::Cached(file));
+        if has_valid_magic(&mut decrypted_file, entry.format) {
+            continue;
+        }
+        warn!(
+            "Cached file {} of {} is corrupt, removing it",
+            entry.file_id, track
+        );
+        if let Err(e) = cache.remove_file(entry.file_id) {
+            warn!("Unable to remove {} from the cache: {}", entry.file_id, e);
+            continue;
+        }
+        offline.remove(&track);
+        removed += 1;
+        freed += size;
+    }
+    (removed, freed)
+}
+
+/// Read the normalisation values of a decrypted Spotify Ogg file, leaving it rewound.
+fn read_normalisation<T: Read + Seek>(file: &mut T) -> Option<Normalisation> {
+    let mut data = [0u8; 16];
+    let result = file
+        .seek(SeekFrom::Start(SPOTIFY_NORMALISATION_OFFSET))
+        .and_then(|_| file.read_exact(&mut data));
+    if let Err(e) = file.seek(SeekFrom::Start(0)) {
+        debug!("Cannot rewind the file: {}", e);
+    }
+    if let Err(e) = result {
+        debug!("Cannot read the normalisation data: {}", e);
+        return None;
+    }
+    let value = |index: usize| f32::from_le_bytes(data[index..index + 4].try_into().unwrap());
+    let normalisation = Normalisation {
+        track_gain_db: value(0),
+        track_peak: value(4),
+        album_gain_db: value(8),
+        album_peak: value(12),
+    };
+    let values = [
+        normalisation.track_gain_db,
+        normalisation.track_peak,
+        normalisation.album_gain_db,
+        normalisation.album_peak,
+    ];
+    values
+        .iter()
+        .all(|value| value.is_finite())
+        .then_some(normalisation)
+}
+
+/// Check that a decrypted file starts like its format should.
+fn has_valid_magic<T: Read + Seek>(file: &mut T, format: AudioFileFormat) -> bool {
+    let mut magic = [0u8; 4];
+    let valid = match file.read_exact(&mut magic) {
+        Ok(()) if AudioFiles::is_ogg_vorbis(format) => &magic == b"OggS",
+        Ok(()) if AudioFiles::is_mp3(format) => {
+            &magic[..3] == b"ID3" || (magic[0] == 0xff && magic[1] & 0xe0 == 0xe0)
+        }
+        Ok(()) if AudioFiles::is_flac(format) => &magic == b"fLaC",
+        // Either an ADTS stream, or an MP4 container starting with the size of its first box.
+        Ok(()) if is_aac(format) => (magic[0] == 0xff && magic[1] & 0xf6 == 0xf0) || magic[0] == 0,
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Cannot read the start of the file: {}", e);
+            false
+        }
+    };
+    if let Err(e) = file.seek(SeekFrom::Start(0)) {
+        debug!("Cannot rewind the file: {}", e);
+        return false;
+    }
+    valid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot_core::config::SessionConfig;
+
+    const TRACK: &str = "spotify:track:4uLU6hMCjMI75M1A2tKUQC";
+
+    fn test_loader() -> TrackLoader {
+        TrackLoader::new(Session::new(SessionConfig::default(), None), None)
+    }
+
+    #[tokio::test]
+    async fn close_drops_the_track_with_its_last_reference() {
+        let mut loader = test_loader();
+        let track = SpotifyId::from_uri(TRACK).unwrap();
+        loader.open_placeholder(track, 1_000);
+        loader.open_placeholder(track, 1_000);
+        assert_eq!(loader.get_opened(&track).unwrap().ref_count(), 2);
+
+        loader.close(&track).unwrap();
+        assert_eq!(loader.get_opened(&track).unwrap().ref_count(), 1);
+        loader.close(&track).unwrap();
+        assert!(loader.get_opened(&track).is_none());
+
+        assert!(matches!(loader.close(&track), Err(LoaderError::NotOpen)));
+    }
+
+    #[tokio::test]
+    async fn close_without_reference_left_fails() {
+        let mut loader = test_loader();
+        let track = SpotifyId::from_uri(TRACK).unwrap();
+        let opened = loader.open_placeholder(track, 1_000);
+        assert_eq!(opened.decr_ref(), Some(0));
+
+        assert!(matches!(
+            loader.close(&track),
+            Err(LoaderError::NotReferenced)
+        ));
+        // The count doesn't underflow, and the track is left for `close_all` to clean up.
+        assert_eq!(loader.get_opened(&track).unwrap().ref_count(), 0);
+        assert_eq!(loader.close_all(), 1);
     }
 }
@@ -1,2 +1,6 @@
+pub mod error;
 pub mod loader;
+pub mod offline;
+pub mod remux;
 pub mod track;
+pub mod wav;
@@ -0,0 +1,106 @@
+use std::{fs, path::PathBuf};
+
+use librespot_core::{audio_key::AudioKey, FileId, SpotifyId};
+use librespot_metadata::audio::AudioFileFormat;
+use log::{debug, warn};
+use protobuf::Enum;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::{decode_hex, encode_hex, parse_file_id};
+
+/// What's needed to play a track from the audio cache without a session.
+pub struct OfflineTrack {
+    pub file_id: FileId,
+    pub format: AudioFileFormat,
+    pub key: AudioKey,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    file_id: String,
+    format: i32,
+    key: String,
+}
+
+/// librespot caches the encrypted audio files but neither the audio keys nor which file
+/// belongs to which track, so keep track of these next to the cache.
+#[derive(Clone)]
+pub struct OfflineIndex {
+    dir: PathBuf,
+}
+
+impl OfflineIndex {
+    pub fn new(dir: PathBuf) -> Option<Self> {
+        if let Err(e) = fs::create_dir_all(&dir) {
+            warn!("Offline playback disabled, cannot create {:?}: {}", dir, e);
+            return None;
+        }
+        Some(Self { dir })
+    }
+    fn path(&self, track: &SpotifyId) -> Option<PathBuf> {
+        let id = track.to_base62().ok()?;
+        Some(self.dir.join(format!("{}.json", id)))
+    }
+    pub fn save(
+        &self,
+        track: &SpotifyId,
+        file_id: FileId,
+        format: AudioFileFormat,
+        key: &AudioKey,
+    ) {
+        let Some(path) = self.path(track) else {
+            return;
+        };
+        let entry = Entry {
+            file_id: file_id.to_string(),
+            format: format.value(),
+            key: encode_hex(&key.0),
+        };
+        let result = serde_json::to_vec(&entry)
+            .map_err(|e| e.to_string())
+            .and_then(|data| fs::write(&path, data).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("Unable to save offline entry for {}: {}", track, e);
+        }
+    }
+    /// Tracks having an entry, whether or not their file is still cached.
+    pub fn tracks(&self) -> Vec<SpotifyId> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Unable to list the offline entries: {}", e);
+                return vec![];
+            }
+        };
+        entries
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                let id = name.to_str()?.strip_suffix(".json")?;
+                SpotifyId::from_base62(id).ok()
+            })
+            .collect()
+    }
+    pub fn remove(&self, track: &SpotifyId) {
+        let Some(path) = self.path(track) else {
+            return;
+        };
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Unable to remove offline entry for {}: {}", track, e);
+        }
+    }
+    pub fn get(&self, track: &SpotifyId) -> Option<OfflineTrack> {
+        let data = fs::read(self.path(track)?).ok()?;
+        let entry: Entry = match serde_json::from_slice(&data) {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("Ignoring malformed offline entry for {}: {}", track, e);
+                return None;
+            }
+        };
+        Some(OfflineTrack {
+            file_id: parse_file_id(&entry.file_id)?,
+            format: AudioFileFormat::from_i32(entry.format)?,
+            key: AudioKey(decode_hex(&entry.key)?.try_into().ok()?),
+        })
+    }
+}
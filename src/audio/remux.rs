@@ -0,0 +1,199 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+// Once Spotify's custom first page is skipped, the remaining pages start at sequence number 1
+// and none carries the beginning-of-stream flag, which strict demuxers reject. `OggRemux`
+// rewrites the page headers on the fly into a standalone stream. Pages keep their size, so
+// offsets in the remuxed stream are the same as in the source one.
+
+const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+const HEADER_SIZE: usize = 27;
+
+const FLAG_BOS: u8 = 0x02;
+const FLAG_EOS: u8 = 0x04;
+
+// Offsets of the fields rewritten in the page header.
+const FLAGS_OFFSET: usize = 5;
+const GRANULE_OFFSET: usize = 6;
+const SEQUENCE_OFFSET: usize = 18;
+const CRC_OFFSET: usize = 22;
+
+// Granule position of pages on which no packet ends.
+const NO_GRANULE: u64 = u64::MAX;
+
+struct Page {
+    offset: u64,
+    size: usize,
+    granule: u64,
+}
+
+pub struct OggRemux<T: Read + Seek> {
+    stream: T,
+    // Pages found so far, indexed by their new sequence number.
+    pages: Vec<Page>,
+    // Whether `pages` covers the whole stream.
+    indexed: bool,
+    // Index and rewritten content of the page being read.
+    current: Option<(usize, Vec<u8>)>,
+    position: u64,
+}
+
+impl<T: Read + Seek> OggRemux<T> {
+    pub fn new(stream: T) -> Self {
+        Self {
+            stream,
+            pages: vec![],
+            indexed: false,
+            current: None,
+            position: 0,
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.pages
+            .last()
+            .map(|page| page.offset + page.size as u64)
+            .unwrap_or(0)
+    }
+
+    /// Read the header of the page starting at `offset`, returning its size and granule
+    /// position, or `None` at the end of the stream.
+    fn read_header(&mut self, offset: u64) -> io::Result<Option<(usize, u64)>> {
+        self.stream.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; HEADER_SIZE];
+        let mut read = 0;
+        while read < HEADER_SIZE {
+            match self.stream.read(&mut header[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => read += n,
+            }
+        }
+        if &header[..4] != CAPTURE_PATTERN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no Ogg page at offset {}", offset),
+            ));
+        }
+        let mut segments = vec![0u8; header[HEADER_SIZE - 1] as usize];
+        self.stream.read_exact(&mut segments)?;
+        let body: usize = segments.iter().map(|&size| size as usize).sum();
+        let granule = u64::from_le_bytes(
+            header[GRANULE_OFFSET..GRANULE_OFFSET + 8]
+                .try_into()
+                .unwrap(),
+        );
+        Ok(Some((HEADER_SIZE + segments.len() + body, granule)))
+    }
+
+    /// Index pages until the one containing `position`, or the end of the stream.
+    fn index_until(&mut self, position: u64) -> io::Result<()> {
+        while !self.indexed && self.end() <= position {
+            let offset = self.end();
+            match self.read_header(offset)? {
+                Some((size, mut granule)) => {
+                    // Keep granule positions non-decreasing across the stream.
+                    if granule != NO_GRANULE {
+                        let previous = self
+                            .pages
+                            .iter()
+                            .rev()
+                            .map(|page| page.granule)
+                            .find(|&granule| granule != NO_GRANULE);
+                        granule = granule.max(previous.unwrap_or(0));
+                    }
+                    self.pages.push(Page {
+                        offset,
+                        size,
+                        granule,
+                    });
+                }
+                None => self.indexed = true,
+            }
+        }
+        Ok(())
+    }
+
+    fn load_page(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        // Make sure we know whether a page follows, to flag the last one.
+        let (offset, size, granule) = {
+            let page = &self.pages[index];
+            (page.offset, page.size, page.granule)
+        };
+        self.index_until(offset + size as u64)?;
+
+        self.stream.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; size];
+        self.stream.read_exact(&mut data)?;
+
+        let mut flags = data[FLAGS_OFFSET] & !(FLAG_BOS | FLAG_EOS);
+        if index == 0 {
+            flags |= FLAG_BOS;
+        }
+        if self.indexed && index == self.pages.len() - 1 {
+            flags |= FLAG_EOS;
+        }
+        data[FLAGS_OFFSET] = flags;
+        data[GRANULE_OFFSET..GRANULE_OFFSET + 8].copy_from_slice(&granule.to_le_bytes());
+        data[SEQUENCE_OFFSET..SEQUENCE_OFFSET + 4].copy_from_slice(&(index as u32).to_le_bytes());
+        data[CRC_OFFSET..CRC_OFFSET + 4].fill(0);
+        let crc = crc32(&data);
+        data[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+        Ok(data)
+    }
+}
+
+impl<T: Read + Seek> Read for OggRemux<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.index_until(self.position)?;
+        if self.position >= self.end() {
+            return Ok(0);
+        }
+        let index = self
+            .pages
+            .partition_point(|page| page.offset + page.size as u64 <= self.position);
+        if !matches!(self.current, Some((current, _)) if current == index) {
+            self.current = Some((index, self.load_page(index)?));
+        }
+        let (_, data) = self.current.as_ref().unwrap();
+        let start = (self.position - self.pages[index].offset) as usize;
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<T: Read + Seek> Seek for OggRemux<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => {
+                self.index_until(u64::MAX)?;
+                self.end().checked_add_signed(offset)
+            }
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// CRC-32 as used by Ogg: polynomial 0x04c11db7, no reflection, zero initial value.
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |mut crc, &byte| {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
@@ -1,18 +1,53 @@
 use std::{
     io::{Read, Seek, SeekFrom},
     sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
 };
 
 use librespot_audio::StreamLoaderController;
 use librespot_metadata::audio::AudioFileFormat;
+use log::error;
+
+use super::{
+    loader::TrackLoader,
+    remux::OggRemux,
+    wav::{self, WavStream},
+};
+
+// Seconds of audio fetched right after a seek.
+const SEEK_PREFETCH_SECS: usize = 5;
+
+/// Loudness normalisation values Spotify embeds in its Ogg files, in the ReplayGain sense.
+#[derive(Clone, Copy, Debug)]
+pub struct Normalisation {
+    pub track_gain_db: f32,
+    pub track_peak: f32,
+    pub album_gain_db: f32,
+    pub album_peak: f32,
+}
 
 pub trait SeekRead: Seek + Read {}
 impl<T: Seek + Read> SeekRead for T {}
 pub struct OpenedTrack {
+    name: String,
     file: Box<dyn SeekRead + Send + Sync>,
-    controller: StreamLoaderController,
+    // Unset for placeholders, which aren't fetched.
+    controller: Option<StreamLoaderController>,
+    // Size of the whole file, header included.
+    file_len: usize,
     ref_count: AtomicU16,
+    // Bytes at the start of the file which aren't served, i.e. Spotify's Ogg header.
+    header_len: u64,
     audio_format: AudioFileFormat,
+    requested_format: AudioFileFormat,
+    cached: bool,
+    remuxed: bool,
+    // Duration used to size the WAV output, when the track is served decoded.
+    decoded: Option<u32>,
+    decoded_len: usize,
+    placeholder: bool,
+    load_time: Duration,
+    normalisation: Option<Normalisation>,
 }
 
 impl OpenedTrack {
@@ -20,31 +55,225 @@ impl OpenedTrack {
         file: Box<dyn SeekRead + Send + Sync>,
         controller: StreamLoaderController,
         audio_format: AudioFileFormat,
+        cached: bool,
     ) -> Self {
         Self {
+            name: String::new(),
             file,
-            controller,
+            file_len: controller.len(),
+            controller: Some(controller),
+            header_len: 0,
             audio_format,
+            requested_format: audio_format,
+            cached,
+            remuxed: false,
+            decoded: None,
+            decoded_len: 0,
+            placeholder: false,
+            load_time: Duration::ZERO,
+            normalisation: None,
+            ref_count: AtomicU16::new(1),
+        }
+    }
+    /// A WAV file of silence, standing in for a track that couldn't be opened.
+    pub fn placeholder(duration_ms: u32) -> Self {
+        let silence = wav::silence(duration_ms);
+        Self {
+            name: String::new(),
+            decoded_len: silence.len(),
+            file_len: silence.len(),
+            file: Box::new(std::io::Cursor::new(silence)),
+            controller: None,
+            header_len: 0,
+            audio_format: AudioFileFormat::MP3_96,
+            requested_format: AudioFileFormat::MP3_96,
+            cached: false,
+            remuxed: false,
+            decoded: Some(duration_ms),
+            placeholder: true,
+            load_time: Duration::ZERO,
+            normalisation: None,
             ref_count: AtomicU16::new(1),
         }
     }
+    pub fn is_placeholder(&self) -> bool {
+        self.placeholder
+    }
+    /// Record how long resolving and opening the track took.
+    pub fn with_load_time(mut self, load_time: Duration) -> Self {
+        self.load_time = load_time;
+        self
+    }
+    pub fn load_time(&self) -> Duration {
+        self.load_time
+    }
+    pub fn with_normalisation(mut self, normalisation: Option<Normalisation>) -> Self {
+        self.normalisation = normalisation;
+        self
+    }
+    pub fn normalisation(&self) -> Option<Normalisation> {
+        self.normalisation
+    }
+    /// Skip the first `header_len` bytes of the file, as done by the `Subfile` it is read from.
+    pub fn with_header_len(mut self, header_len: u64) -> Self {
+        self.header_len = header_len;
+        self
+    }
+    /// Human readable name of the track, for logging.
+    pub fn with_name(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Record the format that would ideally have been served for this track.
+    pub fn with_requested_format(mut self, requested_format: AudioFileFormat) -> Self {
+        self.requested_format = requested_format;
+        self
+    }
+    /// Swap the underlying file and its controller for freshly loaded ones.
+    pub fn rebind(&mut self, other: OpenedTrack) {
+        self.file = other.file;
+        self.controller = other.controller;
+        self.file_len = other.file_len;
+        self.header_len = other.header_len;
+        self.placeholder = other.placeholder;
+        self.normalisation = other.normalisation;
+        self.audio_format = other.audio_format;
+        self.requested_format = other.requested_format;
+        self.cached = other.cached;
+        if self.remuxed {
+            self.remuxed = false;
+            self.remux_ogg();
+        }
+        if let Some(duration_ms) = self.decoded.take() {
+            if let Err(e) = self.decode_wav(duration_ms) {
+                error!("Unable to decode the reloaded track: {}", e);
+            }
+        }
+    }
+    /// Take a reference on the track. Returns the number of references it now has.
     pub fn incr_ref(&self) -> u16 {
-        self.ref_count.fetch_add(1, Ordering::AcqRel)
+        self.ref_count.fetch_add(1, Ordering::AcqRel) + 1
+    }
+    /// Drop a reference on the track. Returns the number of references left, or `None` when it
+    /// had none to drop, in which case the count is left untouched.
+    pub fn decr_ref(&self) -> Option<u16> {
+        self.ref_count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                count.checked_sub(1)
+            })
+            .ok()
+            .map(|previous| previous - 1)
     }
-    pub fn decr_ref(&self) -> u16 {
-        self.ref_count.fetch_sub(1, Ordering::AcqRel)
+    pub fn ref_count(&self) -> u16 {
+        self.ref_count.load(Ordering::Acquire)
     }
+    /// Number of bytes served for the track, which excludes the header skipped from its file.
     pub fn len(&self) -> usize {
-        self.controller.len()
+        if self.decoded.is_some() {
+            self.decoded_len
+        } else {
+            self.file_len.saturating_sub(self.header_len as usize)
+        }
     }
     pub fn format(&self) -> AudioFileFormat {
         self.audio_format
     }
+    pub fn requested_format(&self) -> AudioFileFormat {
+        self.requested_format
+    }
+    /// Start fetching the region following the current position, ahead of it being read.
+    pub fn prefetch(&self) {
+        if let Some(controller) = &self.controller {
+            controller
+                .fetch_next(TrackLoader::stream_data_rate(self.audio_format) * SEEK_PREFETCH_SECS);
+        }
+    }
+    /// Read ahead of the current position, while the track is streamed to a client.
+    pub fn start_read_ahead(&self) {
+        if let Some(controller) = &self.controller {
+            controller.set_stream_mode();
+        }
+    }
+    /// Only fetch the regions being read, once no client streams the track anymore.
+    pub fn stop_read_ahead(&self) {
+        if let Some(controller) = &self.controller {
+            controller.set_random_access_mode();
+        }
+    }
+    /// Stop fetching the file altogether, once the track is closed.
+    pub fn stop_fetching(&self) {
+        if let Some(controller) = &self.controller {
+            controller.close();
+        }
+    }
+    /// Whether the served format has a lower bitrate than the requested one.
+    pub fn is_downgraded(&self) -> bool {
+        TrackLoader::stream_data_rate(self.audio_format)
+            < TrackLoader::stream_data_rate(self.requested_format)
+    }
+    /// Whether the file was served from the local cache rather than the network.
+    pub fn is_cached(&self) -> bool {
+        self.cached
+    }
+    /// Serve the file as a standards-compliant Ogg stream. Only meaningful for Ogg formats.
+    pub fn remux_ogg(&mut self) {
+        if self.remuxed || self.decoded.is_some() {
+            return;
+        }
+        let file = std::mem::replace(&mut self.file, Box::new(std::io::empty()));
+        self.file = Box::new(OggRemux::new(file));
+        self.remuxed = true;
+    }
+    /// Serve the file decoded, as a 16-bit PCM WAV file sized after `duration_ms`. Not to be
+    /// combined with `remux_ogg`.
+    pub fn decode_wav(&mut self, duration_ms: u32) -> std::io::Result<()> {
+        if self.decoded.is_some() {
+            return Ok(());
+        }
+        let file = std::mem::replace(&mut self.file, Box::new(std::io::empty()));
+        let wav = WavStream::new(file, self.audio_format, duration_ms)?;
+        self.decoded_len = wav.len() as usize;
+        self.file = Box::new(wav);
+        self.decoded = Some(duration_ms);
+        Ok(())
+    }
+    pub fn is_decoded(&self) -> bool {
+        self.decoded.is_some()
+    }
+    /// Duration guessed from the file size and nominal bitrate, for when metadata is missing.
+    pub fn estimated_duration_ms(&self) -> u32 {
+        (self.file_len as u64 * 1000 / TrackLoader::stream_data_rate(self.audio_format) as u64)
+            as u32
+    }
+    /// Seek to `position_ms` into the track. Exact when decoded, otherwise estimated from the
+    /// nominal bitrate of the format, which VBR files only follow on average.
+    pub fn seek_time(&mut self, position_ms: u32) -> std::io::Result<u64> {
+        let position = if self.decoded.is_some() {
+            wav::position_at(position_ms)
+        } else {
+            position_ms as u64 * TrackLoader::stream_data_rate(self.audio_format) as u64 / 1000
+        };
+        self.seek(SeekFrom::Start(position.min(self.len() as u64)))
+    }
+    /// Read up to `size` bytes from the start of the track, leaving the position untouched.
+    pub fn peek(&mut self, size: usize) -> std::io::Result<Vec<u8>> {
+        let position = self.file.stream_position()?;
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut header = Vec::with_capacity(size);
+        let result = (&mut self.file).take(size as u64).read_to_end(&mut header);
+        self.file.seek(SeekFrom::Start(position))?;
+        result.map(|_| header)
+    }
 }
 
+/// View of `stream` starting at `offset`, positions being relative to it.
 pub struct Subfile<T: Read + Seek> {
     stream: T,
     offset: u64,
+    /// Length of the whole stream, which is also the end of the subfile.
     length: u64,
 }
 
@@ -69,21 +298,23 @@ impl<T: Read + Seek> Read for Subfile<T> {
 
 impl<T: Read + Seek> Seek for Subfile<T> {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
-        let pos = match pos {
-            SeekFrom::Start(offset) => SeekFrom::Start(offset + self.offset),
-            SeekFrom::End(offset) => {
-                if (self.length as i64 - offset) < self.offset as i64 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "newpos would be < self.offset",
-                    ));
-                }
-                pos
-            }
-            _ => pos,
-        };
+        let target = match pos {
+            SeekFrom::Start(offset) => self.offset.checked_add(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.stream.stream_position()?.checked_add_signed(offset),
+        }
+        .filter(|target| *target >= self.offset)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "newpos would be < self.offset",
+            )
+        })?;
 
-        let newpos = self.stream.seek(pos)?;
+        // Seeking past the end behaves as seeking to it, where reads return EOF.
+        let newpos = self
+            .stream
+            .seek(SeekFrom::Start(target.min(self.length.max(self.offset))))?;
         Ok(newpos - self.offset)
     }
 }
@@ -99,3 +330,134 @@ impl Seek for OpenedTrack {
         self.file.seek(pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot_audio::AudioFile;
+    use std::io::Cursor;
+
+    // A subfile of the bytes 0 to 99, skipping the first 10, so its positions map to the values
+    // read plus 10.
+    fn subfile() -> Subfile<Cursor<Vec<u8>>> {
+        Subfile::new(Cursor::new((0..100).collect()), 10, 100).unwrap()
+    }
+
+    // An Ogg file as Spotify serves it: a custom first page of `SPOTIFY_HEADER_LEN` bytes, then
+    // regular pages.
+    const SPOTIFY_HEADER_LEN: u64 = 0xa7;
+
+    fn ogg_page(granule: u64, body_len: u8) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.extend([0, 0]);
+        page.extend(granule.to_le_bytes());
+        page.extend([0u8; 12]);
+        page.extend([1, body_len]);
+        page.extend(vec![0x55; body_len as usize]);
+        page
+    }
+
+    // Opened as the loader does for cached files, whose controller only knows their size.
+    fn spotify_ogg() -> OpenedTrack {
+        let mut data = vec![0u8; SPOTIFY_HEADER_LEN as usize];
+        data.extend(ogg_page(0, 30));
+        data.extend(ogg_page(960, 255));
+        data.extend(ogg_page(1920, 17));
+        let path = std::env::temp_dir().join(format!("spotify-ogg-{}", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let cached = AudioFile::Cached(std::fs::File::open(&path).unwrap());
+        let controller = cached.get_stream_loader_controller().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let file_len = data.len() as u64;
+        let file = Subfile::new(Cursor::new(data), SPOTIFY_HEADER_LEN, file_len).unwrap();
+        OpenedTrack::new(
+            Box::new(file),
+            controller,
+            AudioFileFormat::OGG_VORBIS_160,
+            true,
+        )
+        .with_header_len(SPOTIFY_HEADER_LEN)
+        .with_requested_format(AudioFileFormat::OGG_VORBIS_320)
+    }
+
+    fn read_all(track: &mut OpenedTrack) -> usize {
+        let mut data = vec![];
+        track.read_to_end(&mut data).unwrap()
+    }
+
+    #[test]
+    fn remuxed_len_matches_bytes_read() {
+        let mut track = spotify_ogg();
+        track.remux_ogg();
+        let len = track.len();
+        assert_eq!(len, 3 * 28 + 30 + 255 + 17);
+        assert_eq!(read_all(&mut track), len);
+    }
+
+    #[test]
+    fn wav_len_matches_bytes_read() {
+        let mut track = OpenedTrack::placeholder(1_500);
+        let len = track.len();
+        assert!(len > 0);
+        assert_eq!(read_all(&mut track), len);
+    }
+
+    #[test]
+    fn decr_ref_stops_at_zero() {
+        let track = OpenedTrack::placeholder(1_000);
+        assert_eq!(track.incr_ref(), 2);
+        assert_eq!(track.decr_ref(), Some(1));
+        assert_eq!(track.decr_ref(), Some(0));
+        assert_eq!(track.decr_ref(), None);
+        assert_eq!(track.ref_count(), 0);
+    }
+
+    fn next_byte<T: Read>(file: &mut T) -> Option<u8> {
+        let mut byte = [0u8];
+        match file.read(&mut byte).unwrap() {
+            0 => None,
+            _ => Some(byte[0]),
+        }
+    }
+
+    #[test]
+    fn subfile_seek_from_start() {
+        let mut file = subfile();
+        assert_eq!(file.seek(SeekFrom::Start(0)).unwrap(), 0);
+        assert_eq!(next_byte(&mut file), Some(10));
+        assert_eq!(file.seek(SeekFrom::Start(89)).unwrap(), 89);
+        assert_eq!(next_byte(&mut file), Some(99));
+        assert_eq!(file.seek(SeekFrom::Start(90)).unwrap(), 90);
+        assert_eq!(next_byte(&mut file), None);
+        // Past the end is clamped to it.
+        assert_eq!(file.seek(SeekFrom::Start(200)).unwrap(), 90);
+        assert_eq!(next_byte(&mut file), None);
+    }
+
+    #[test]
+    fn subfile_seek_from_end() {
+        let mut file = subfile();
+        assert_eq!(file.seek(SeekFrom::End(0)).unwrap(), 90);
+        assert_eq!(next_byte(&mut file), None);
+        assert_eq!(file.seek(SeekFrom::End(-10)).unwrap(), 80);
+        assert_eq!(next_byte(&mut file), Some(90));
+        assert_eq!(file.seek(SeekFrom::End(-90)).unwrap(), 0);
+        assert_eq!(next_byte(&mut file), Some(10));
+        assert_eq!(file.seek(SeekFrom::End(5)).unwrap(), 90);
+        assert!(file.seek(SeekFrom::End(-91)).is_err());
+    }
+
+    #[test]
+    fn subfile_seek_from_current() {
+        let mut file = subfile();
+        file.seek(SeekFrom::Start(20)).unwrap();
+        assert_eq!(file.seek(SeekFrom::Current(5)).unwrap(), 25);
+        assert_eq!(next_byte(&mut file), Some(35));
+        assert_eq!(file.seek(SeekFrom::Current(-26)).unwrap(), 0);
+        assert!(file.seek(SeekFrom::Current(-1)).is_err());
+        assert_eq!(file.seek(SeekFrom::Current(90)).unwrap(), 90);
+        assert_eq!(file.seek(SeekFrom::Current(1_000)).unwrap(), 90);
+        assert_eq!(next_byte(&mut file), None);
+    }
+}
@@ -0,0 +1,253 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use librespot_metadata::audio::AudioFileFormat;
+use librespot_playback::{
+    decoder::{AudioDecoder, SymphoniaDecoder},
+    NUM_CHANNELS, SAMPLE_RATE,
+};
+use log::{debug, warn};
+use symphonia::core::io::MediaSource;
+
+use super::track::SeekRead;
+
+// Decoded tracks are served as 16-bit PCM, at the rate and channel count of librespot's
+// decoders.
+const BITS_PER_SAMPLE: u16 = 16;
+const FRAME_SIZE: u64 = NUM_CHANNELS as u64 * BITS_PER_SAMPLE as u64 / 8;
+const HEADER_SIZE: u64 = 44;
+
+// Read positions further than this past the decoded audio make the decoder seek.
+const MAX_DECODE_AHEAD: u64 = SAMPLE_RATE as u64 * FRAME_SIZE;
+
+// The decoded length of a VBR track isn't known until it's fully decoded, but Mixxx needs a
+// correct data length to seek. So the length is computed upfront from the track duration as
+// given by its metadata, and the decoded audio is padded with silence or truncated to match.
+// This keeps the byte to time mapping trivial, at the cost of a few milliseconds of drift at
+// the very end of the track.
+
+/// Serve a track as a seekable WAV file, decoding it on the fly.
+pub struct WavStream {
+    decoder: SymphoniaDecoder,
+    header: [u8; HEADER_SIZE as usize],
+    data_len: u64,
+    position: u64,
+    // Decoded samples, and their offset in the data chunk.
+    buffer: Vec<u8>,
+    buffer_start: u64,
+    // Set once the decoder is exhausted, from then on only silence is served.
+    ended: bool,
+}
+
+struct Source {
+    file: Box<dyn SeekRead + Send + Sync>,
+    len: u64,
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for Source {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl MediaSource for Source {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+}
+
+impl WavStream {
+    pub fn new(
+        mut file: Box<dyn SeekRead + Send + Sync>,
+        format: AudioFileFormat,
+        duration_ms: u32,
+    ) -> io::Result<Self> {
+        let len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        let decoder = SymphoniaDecoder::new(Source { file, len }, format)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        let data_len = frame_at(duration_ms) * FRAME_SIZE;
+        Ok(Self {
+            decoder,
+            header: header(data_len),
+            data_len,
+            position: 0,
+            buffer: vec![],
+            buffer_start: 0,
+            ended: false,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        HEADER_SIZE + self.data_len
+    }
+
+    fn buffer_end(&self) -> u64 {
+        self.buffer_start + self.buffer.len() as u64
+    }
+
+    /// Decode the next packet into the buffer, returning false once the track is over.
+    fn decode_next(&mut self) -> io::Result<bool> {
+        if self.ended {
+            return Ok(false);
+        }
+        let start = self.buffer_end();
+        self.buffer.clear();
+        self.buffer_start = start;
+        loop {
+            let packet = self
+                .decoder
+                .next_packet()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let Some((_, packet, _)) = packet else {
+                if start < self.data_len {
+                    warn!(
+                        "Decoded track is {} bytes short, padding it with silence",
+                        self.data_len - start
+                    );
+                }
+                self.ended = true;
+                return Ok(false);
+            };
+            let samples = match packet.samples() {
+                Ok(samples) => samples,
+                // Not audio, e.g. Ogg comments.
+                Err(_) => continue,
+            };
+            self.buffer.reserve(samples.len() * 2);
+            for sample in samples {
+                let sample = (sample * i16::MAX as f64)
+                    .round()
+                    .clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+                self.buffer.extend_from_slice(&sample.to_le_bytes());
+            }
+            if !self.buffer.is_empty() {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Reposition the decoder so that the buffer ends at or before `offset` in the data chunk.
+    /// The decoded audio stays at the position the decoder actually landed on, when it lands
+    /// past `offset` the gap is served as silence.
+    fn seek_decoder(&mut self, offset: u64) -> io::Result<()> {
+        let position_ms = (offset / FRAME_SIZE * 1000 / SAMPLE_RATE as u64) as u32;
+        let actual_ms = self
+            .decoder
+            .seek(position_ms)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        debug!("Decoder seeked to {}ms for {}ms", actual_ms, position_ms);
+        self.buffer.clear();
+        let actual_start = frame_at(actual_ms) * FRAME_SIZE;
+        self.buffer_start = actual_start.min(offset - offset % FRAME_SIZE);
+        self.buffer
+            .resize((actual_start - self.buffer_start) as usize, 0);
+        self.ended = false;
+        Ok(())
+    }
+}
+
+impl Read for WavStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position < HEADER_SIZE {
+            let header = &self.header[self.position as usize..];
+            let len = buf.len().min(header.len());
+            buf[..len].copy_from_slice(&header[..len]);
+            self.position += len as u64;
+            return Ok(len);
+        }
+        let offset = self.position - HEADER_SIZE;
+        if offset >= self.data_len {
+            return Ok(0);
+        }
+
+        // Decode forward for short jumps, seeking is only worth it further away.
+        if offset < self.buffer_start || offset > self.buffer_end() + MAX_DECODE_AHEAD {
+            self.seek_decoder(offset)?;
+        }
+        while offset >= self.buffer_end() {
+            if !self.decode_next()? {
+                break;
+            }
+        }
+
+        let len = buf.len().min((self.data_len - offset) as usize);
+        let len = if offset < self.buffer_end() {
+            let start = (offset - self.buffer_start) as usize;
+            let len = len.min(self.buffer.len() - start);
+            buf[..len].copy_from_slice(&self.buffer[start..start + len]);
+            len
+        } else {
+            // The track decoded shorter than its metadata duration.
+            buf[..len].fill(0);
+            len
+        };
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for WavStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// A WAV file of `duration_ms` of silence.
+pub fn silence(duration_ms: u32) -> Vec<u8> {
+    let data_len = frame_at(duration_ms) * FRAME_SIZE;
+    let mut wav = header(data_len).to_vec();
+    wav.resize((HEADER_SIZE + data_len) as usize, 0);
+    wav
+}
+
+/// Byte position of `position_ms` in a WAV file served by `WavStream`.
+pub fn position_at(position_ms: u32) -> u64 {
+    HEADER_SIZE + frame_at(position_ms) * FRAME_SIZE
+}
+
+fn frame_at(position_ms: u32) -> u64 {
+    position_ms as u64 * SAMPLE_RATE as u64 / 1000
+}
+
+fn header(data_len: u64) -> [u8; HEADER_SIZE as usize] {
+    let byte_rate = SAMPLE_RATE * FRAME_SIZE as u32;
+    let data_len = data_len.min(u32::MAX as u64 - HEADER_SIZE) as u32;
+
+    let mut header = [0u8; HEADER_SIZE as usize];
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&(data_len + HEADER_SIZE as u32 - 8).to_le_bytes());
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&16u32.to_le_bytes());
+    // PCM
+    header[20..22].copy_from_slice(&1u16.to_le_bytes());
+    header[22..24].copy_from_slice(&(NUM_CHANNELS as u16).to_le_bytes());
+    header[24..28].copy_from_slice(&SAMPLE_RATE.to_le_bytes());
+    header[28..32].copy_from_slice(&byte_rate.to_le_bytes());
+    header[32..34].copy_from_slice(&(FRAME_SIZE as u16).to_le_bytes());
+    header[34..36].copy_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&data_len.to_le_bytes());
+    header
+}
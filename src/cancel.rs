@@ -0,0 +1,82 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Cancellation flags of the running streams, by stream id, so a client can stop a given
+/// stream without dropping its connection.
+#[derive(Default)]
+pub struct CancelRegistry {
+    next_id: AtomicU64,
+    streams: Mutex<HashMap<String, Arc<AtomicBool>>>,
+}
+
+/// Registration of a stream, removed from the registry once dropped.
+pub struct StreamHandle {
+    registry: Arc<CancelRegistry>,
+    id: String,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelRegistry {
+    /// Register a stream under the id chosen by the client, or a generated one when empty.
+    /// Returns `None` if the id is already in use.
+    pub fn register(self: &Arc<Self>, id: String) -> Option<StreamHandle> {
+        let id = if id.is_empty() {
+            format!("stream-{}", self.next_id.fetch_add(1, Ordering::Relaxed))
+        } else {
+            id
+        };
+        let mut streams = self.streams.lock().unwrap();
+        if streams.contains_key(&id) {
+            return None;
+        }
+        let cancelled = Arc::new(AtomicBool::new(false));
+        streams.insert(id.clone(), Arc::clone(&cancelled));
+        Some(StreamHandle {
+            registry: Arc::clone(self),
+            id,
+            cancelled,
+        })
+    }
+    /// Flag a stream for cancellation, returning whether it was running.
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.streams.lock().unwrap().get(id) {
+            Some(cancelled) => {
+                cancelled.store(true, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Whether no stream is running.
+    pub fn is_empty(&self) -> bool {
+        self.streams.lock().unwrap().is_empty()
+    }
+    /// Flag every running stream for cancellation, returning how many there were.
+    pub fn cancel_all(&self) -> usize {
+        let streams = self.streams.lock().unwrap();
+        for cancelled in streams.values() {
+            cancelled.store(true, Ordering::Release);
+        }
+        streams.len()
+    }
+}
+
+impl StreamHandle {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for StreamHandle {
+    fn drop(&mut self) {
+        self.registry.streams.lock().unwrap().remove(&self.id);
+    }
+}
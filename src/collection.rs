@@ -0,0 +1,82 @@
+use http::{
+    header::{ACCEPT, CONTENT_TYPE},
+    HeaderMap, HeaderValue, Method,
+};
+use librespot_core::{spotify_id::SpotifyItemType, Error, Session, SpotifyId};
+use librespot_protocol::collection2v2::{PageRequest, PageResponse};
+use log::debug;
+use protobuf::Message;
+
+// The user's library is only exposed by the collection service of the Spotify clients, as
+// named sets of items fetched page by page.
+const COLLECTION_ENDPOINT: &str = "/collection/v2/paging";
+const COLLECTION_CONTENT_TYPE: &str = "application/vnd.collection-v2.spotify.proto";
+const PAGE_SIZE: i32 = 300;
+
+/// Episodes saved by the user, as listed in "Your Episodes".
+pub const SAVED_EPISODES: &str = "listenlater";
+/// Tracks liked and albums saved by the user.
+pub const LIKED_SONGS: &str = "collection";
+pub const SAVED_ALBUMS: &str = LIKED_SONGS;
+/// Artists followed by the user.
+pub const FOLLOWED_ARTISTS: &str = "artist";
+/// Shows followed by the user.
+pub const FOLLOWED_SHOWS: &str = "show";
+
+/// List the items of `item_type` in one of the user's collections, most recently added first.
+/// Only the pages needed to list the first `limit` items are fetched, when given.
+pub async fn collection_items(
+    session: &Session,
+    set: &str,
+    item_type: SpotifyItemType,
+    limit: Option<usize>,
+) -> Result<Vec<SpotifyId>, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(COLLECTION_CONTENT_TYPE),
+    );
+    headers.insert(ACCEPT, HeaderValue::from_static(COLLECTION_CONTENT_TYPE));
+
+    let mut items = vec![];
+    let mut pagination_token = String::new();
+    loop {
+        let request = PageRequest {
+            username: session.username(),
+            set: set.to_owned(),
+            pagination_token,
+            limit: PAGE_SIZE,
+            ..PageRequest::default()
+        };
+        let body = request.write_to_bytes()?;
+        let response = session
+            .spclient()
+            .request(
+                &Method::POST,
+                COLLECTION_ENDPOINT,
+                Some(headers.clone()),
+                Some(&body),
+            )
+            .await?;
+        let page = PageResponse::parse_from_bytes(&response)?;
+
+        items.extend(
+            page.items
+                .iter()
+                .filter(|item| !item.is_removed)
+                .filter_map(|item| match SpotifyId::from_uri(&item.uri) {
+                    Ok(id) => Some(id),
+                    Err(e) => {
+                        debug!("Ignoring collection item {}: {}", item.uri, e);
+                        None
+                    }
+                })
+                // Sets may mix several types of items, e.g. tracks and albums.
+                .filter(|id| id.item_type == item_type),
+        );
+        if page.next_page_token.is_empty() || limit.is_some_and(|limit| items.len() >= limit) {
+            return Ok(items);
+        }
+        pagination_token = page.next_page_token;
+    }
+}
@@ -0,0 +1,320 @@
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+use librespot_audio::AudioFetchParams;
+use librespot_metadata::audio::AudioFileFormat;
+use librespot_playback::config::Bitrate;
+use log::warn;
+use url::Url;
+
+use crate::audio::loader::{self, FormatPreference};
+
+const ENV_PREFIX: &str = "MIXXX_PLUGIN_";
+const DEFAULT_SOCKET: &str = "/tmp/mixxx_plugin_test.sock";
+const DEFAULT_CACHE_DIR: &str = "./spotcache";
+const DEFAULT_RESOURCE_DIR: &str = "./res";
+const DEFAULT_CACHE_SIZE_LIMIT: u64 = 1_000_000_000;
+
+// Bounds of the buffer size passed to librespot when opening an audio file.
+const MIN_FETCH_BUFFER_SIZE: usize = 1024;
+const MAX_FETCH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// What to do when a track is opened in a format other than the one it's already open in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormatConflict {
+    /// Share the existing open, ignoring the requested format.
+    Share,
+    /// Fail the new open until the track is closed.
+    Reject,
+}
+
+impl FromStr for FormatConflict {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "share" => Ok(Self::Share),
+            "reject" => Ok(Self::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Runtime settings of the plugin, sourced from `MIXXX_PLUGIN_*` environment variables.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Path of the Unix socket the gRPC server listens on.
+    pub socket: PathBuf,
+    /// Number of times binding the socket is retried after a failure.
+    pub bind_retries: u32,
+    /// URL of the HTTP proxy Spotify is reached through, as given. See `proxy_url`.
+    pub proxy: Option<String>,
+    /// Directory of the audio cache and offline index, `None` to disable caching.
+    pub cache_dir: Option<PathBuf>,
+    /// Directories of the stored credentials and volume, `None` not to store them.
+    pub credentials_dir: Option<PathBuf>,
+    pub volume_dir: Option<PathBuf>,
+    /// Size above which librespot evicts audio files from the cache, if any.
+    pub cache_size_limit: Option<u64>,
+    /// Directory of the QML views, unused when they're embedded at build time.
+    pub resource_dir: PathBuf,
+    /// Disconnect the session after this long without any RPC and no open track.
+    pub idle_timeout: Option<Duration>,
+    /// Interval of the HTTP/2 pings keeping idle streaming connections alive.
+    pub http2_keepalive_interval: Option<Duration>,
+    /// How long to wait for a ping acknowledgement before reaping the connection.
+    pub http2_keepalive_timeout: Option<Duration>,
+    /// Deadline applied to each RPC handler.
+    pub request_timeout: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+    /// Number of tracks returned by `fetch_content` when no limit is given, `0` for all.
+    pub default_page_size: usize,
+    /// Number of tracks of each `fetch_content` page whose audio key is requested ahead of
+    /// their opening, `0` to disable.
+    pub prewarm_tracks: usize,
+    /// Maximum number of `read` streams served at the same time.
+    pub max_read_streams: usize,
+    /// Maximum number of tracks kept loaded without any reference, i.e. preloaded but not
+    /// opened, the least recently used being dropped first.
+    pub max_idle_tracks: usize,
+    /// Rewrite Ogg tracks into a standalone stream that general-purpose players accept.
+    pub ogg_remux: bool,
+    /// Deadline for a track to be opened, retries included.
+    pub open_timeout: Option<Duration>,
+    /// Duration of the silence served in place of a track that can't be opened, if enabled.
+    pub placeholder_duration: Option<Duration>,
+    /// Number of times opening an audio file is retried after a failure.
+    pub open_retries: u32,
+    /// Buffer size used by librespot to fetch audio files. Larger values reduce the request
+    /// overhead on fast links.
+    pub fetch_buffer_size: usize,
+    /// Seconds of audio fetched ahead of the read position before and during playback.
+    pub read_ahead_before_playback: f64,
+    pub read_ahead_during_playback: f64,
+    /// How many round trips ahead of the read position the next region gets fetched.
+    pub prefetch_threshold_factor: f32,
+    /// Pick an already cached alternative of an unavailable track over downloading another.
+    pub prefer_cached_alternative: bool,
+    /// Serve tracks decoded as WAV rather than in their Spotify format.
+    pub decode_wav: bool,
+    /// Bitrate of the player, above which formats are only used as a last resort.
+    pub bitrate: Bitrate,
+    /// Market in which tracks are resolved, instead of the account's country, e.g. when
+    /// connecting through a VPN.
+    pub market: Option<String>,
+    /// Formats to try first when opening a track, before the built-in preference.
+    pub formats: Vec<AudioFileFormat>,
+    /// Prefer the FLAC file of tracks, on accounts allowed to stream it.
+    pub lossless: bool,
+    pub format_conflict: FormatConflict,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            socket: PathBuf::from(DEFAULT_SOCKET),
+            bind_retries: 3,
+            proxy: None,
+            cache_dir: Some(PathBuf::from(DEFAULT_CACHE_DIR)),
+            credentials_dir: Some(PathBuf::from(DEFAULT_CACHE_DIR)),
+            volume_dir: None,
+            resource_dir: PathBuf::from(DEFAULT_RESOURCE_DIR),
+            cache_size_limit: Some(DEFAULT_CACHE_SIZE_LIMIT),
+            idle_timeout: None,
+            http2_keepalive_interval: Some(Duration::from_secs(30)),
+            http2_keepalive_timeout: Some(Duration::from_secs(20)),
+            request_timeout: None,
+            tcp_keepalive: None,
+            default_page_size: 100,
+            prewarm_tracks: 0,
+            max_read_streams: 16,
+            max_idle_tracks: 8,
+            ogg_remux: false,
+            open_timeout: Some(Duration::from_secs(60)),
+            placeholder_duration: None,
+            open_retries: 2,
+            fetch_buffer_size: 10_240,
+            read_ahead_before_playback: 5.0,
+            read_ahead_during_playback: 30.0,
+            prefetch_threshold_factor: 4.0,
+            prefer_cached_alternative: true,
+            decode_wav: false,
+            bitrate: Bitrate::Bitrate320,
+            market: None,
+            formats: vec![],
+            lossless: false,
+            format_conflict: FormatConflict::Share,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let cache_dir = env_dir("CACHE_DIR").unwrap_or(default.cache_dir);
+        Self {
+            socket: env_var("SOCKET").unwrap_or(default.socket),
+            bind_retries: env_var("BIND_RETRIES").unwrap_or(default.bind_retries),
+            // The standard variables are honoured too, with a lower precedence.
+            proxy: env_var::<String>("PROXY")
+                .or_else(|| env::var("HTTPS_PROXY").ok())
+                .or_else(|| env::var("https_proxy").ok())
+                .or_else(|| env::var("HTTP_PROXY").ok())
+                .or_else(|| env::var("http_proxy").ok())
+                .filter(|proxy| !proxy.trim().is_empty())
+                .or(default.proxy),
+            // Credentials are stored along the cache unless set otherwise.
+            credentials_dir: env_dir("CREDENTIALS_DIR").unwrap_or_else(|| cache_dir.clone()),
+            cache_dir,
+            volume_dir: env_dir("VOLUME_DIR").unwrap_or(default.volume_dir),
+            resource_dir: env_dir("RESOURCE_DIR")
+                .flatten()
+                .unwrap_or(default.resource_dir),
+            cache_size_limit: env_var::<u64>("CACHE_SIZE_LIMIT")
+                .map(|limit| Some(limit).filter(|limit| *limit > 0))
+                .unwrap_or(default.cache_size_limit),
+            idle_timeout: env_var::<u64>("IDLE_TIMEOUT_MINUTES")
+                .map(|minutes| Some(Duration::from_secs(minutes * 60)).filter(|d| !d.is_zero()))
+                .unwrap_or(default.idle_timeout),
+            http2_keepalive_interval: env_secs("HTTP2_KEEPALIVE_SECS")
+                .unwrap_or(default.http2_keepalive_interval),
+            http2_keepalive_timeout: env_secs("HTTP2_KEEPALIVE_TIMEOUT_SECS")
+                .unwrap_or(default.http2_keepalive_timeout),
+            request_timeout: env_secs("REQUEST_TIMEOUT_SECS").unwrap_or(default.request_timeout),
+            tcp_keepalive: env_secs("TCP_KEEPALIVE_SECS").unwrap_or(default.tcp_keepalive),
+            default_page_size: env_var("DEFAULT_PAGE_SIZE").unwrap_or(default.default_page_size),
+            prewarm_tracks: env_var("PREWARM_TRACKS").unwrap_or(default.prewarm_tracks),
+            max_read_streams: env_var("MAX_READ_STREAMS")
+                .filter(|max| *max > 0)
+                .unwrap_or(default.max_read_streams),
+            max_idle_tracks: env_var("MAX_IDLE_TRACKS").unwrap_or(default.max_idle_tracks),
+            ogg_remux: env_var("OGG_REMUX").unwrap_or(default.ogg_remux),
+            open_timeout: env_secs("OPEN_TIMEOUT_SECS").unwrap_or(default.open_timeout),
+            placeholder_duration: env_secs("PLACEHOLDER_SECS")
+                .unwrap_or(default.placeholder_duration),
+            open_retries: env_var("OPEN_RETRIES").unwrap_or(default.open_retries),
+            fetch_buffer_size: env_var::<usize>("FETCH_BUFFER_SIZE")
+                .map(|size| {
+                    let bounded = size.clamp(MIN_FETCH_BUFFER_SIZE, MAX_FETCH_BUFFER_SIZE);
+                    if bounded != size {
+                        warn!(
+                            "Fetch buffer size {} out of bounds, using {} instead",
+                            size, bounded
+                        );
+                    }
+                    bounded
+                })
+                .unwrap_or(default.fetch_buffer_size),
+            read_ahead_before_playback: env_var("READ_AHEAD_BEFORE_PLAYBACK_SECS")
+                .unwrap_or(default.read_ahead_before_playback),
+            read_ahead_during_playback: env_var("READ_AHEAD_DURING_PLAYBACK_SECS")
+                .unwrap_or(default.read_ahead_during_playback),
+            prefetch_threshold_factor: env_var("PREFETCH_THRESHOLD_FACTOR")
+                .unwrap_or(default.prefetch_threshold_factor),
+            prefer_cached_alternative: env_var("PREFER_CACHED_ALTERNATIVE")
+                .unwrap_or(default.prefer_cached_alternative),
+            decode_wav: env_var("DECODE_WAV").unwrap_or(default.decode_wav),
+            bitrate: env_var("BITRATE").unwrap_or(default.bitrate),
+            market: env_var::<String>("MARKET")
+                .map(|market| {
+                    let valid =
+                        market.len() == 2 && market.chars().all(|c| c.is_ascii_alphabetic());
+                    if !valid {
+                        warn!(
+                            "Ignoring market {:?}, not a two-letter country code",
+                            market
+                        );
+                    }
+                    Some(market.to_ascii_uppercase()).filter(|_| valid)
+                })
+                .unwrap_or(default.market),
+            formats: env_var::<String>("FORMATS")
+                .map(|formats| {
+                    formats
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .filter_map(|name| {
+                            let format = loader::parse_format(name);
+                            if format.is_none() {
+                                warn!("Ignoring unsupported format {:?}", name);
+                            }
+                            format
+                        })
+                        .collect()
+                })
+                .unwrap_or(default.formats),
+            lossless: env_var("LOSSLESS").unwrap_or(default.lossless),
+            format_conflict: env_var("FORMAT_CONFLICT").unwrap_or(default.format_conflict),
+        }
+    }
+    /// Parameters of librespot's audio fetching, which can only be set once per process.
+    pub fn fetch_params(&self) -> Result<AudioFetchParams, String> {
+        let read_ahead = |name, secs: f64| {
+            Duration::try_from_secs_f64(secs).map_err(|_| {
+                format!(
+                    "{} must be a non-negative number of seconds, got {}",
+                    name, secs
+                )
+            })
+        };
+        if !(self.prefetch_threshold_factor.is_finite() && self.prefetch_threshold_factor > 0.0) {
+            return Err(format!(
+                "prefetch threshold factor must be positive, got {}",
+                self.prefetch_threshold_factor
+            ));
+        }
+        Ok(AudioFetchParams {
+            read_ahead_before_playback: read_ahead(
+                "read ahead before playback",
+                self.read_ahead_before_playback,
+            )?,
+            read_ahead_during_playback: read_ahead(
+                "read ahead during playback",
+                self.read_ahead_during_playback,
+            )?,
+            prefetch_threshold_factor: self.prefetch_threshold_factor,
+            ..AudioFetchParams::default()
+        })
+    }
+    /// The proxy to connect through, if any, as an HTTP(S) URL.
+    pub fn proxy_url(&self) -> Result<Option<Url>, String> {
+        let Some(proxy) = &self.proxy else {
+            return Ok(None);
+        };
+        let url = Url::parse(proxy.trim()).map_err(|e| format!("{:?}: {}", proxy, e))?;
+        if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+            return Err(format!("{:?} is not an HTTP proxy URL", proxy));
+        }
+        Ok(Some(url))
+    }
+    pub fn format_preference(&self) -> FormatPreference {
+        FormatPreference {
+            formats: self.formats.clone(),
+            bitrate: Some(self.bitrate),
+            lossless: self.lossless,
+            accepted: vec![],
+        }
+    }
+}
+
+/// Read a directory, where an empty value explicitly disables the setting.
+fn env_dir(name: &str) -> Option<Option<PathBuf>> {
+    env_var::<PathBuf>(name).map(|dir| Some(dir).filter(|dir| !dir.as_os_str().is_empty()))
+}
+
+/// Read a duration in seconds, where `0` explicitly disables the setting.
+fn env_secs(name: &str) -> Option<Option<Duration>> {
+    env_var::<u64>(name).map(|secs| Some(Duration::from_secs(secs)).filter(|d| !d.is_zero()))
+}
+
+fn env_var<T: FromStr>(name: &str) -> Option<T> {
+    let key = format!("{}{}", ENV_PREFIX, name);
+    let value = env::var(&key).ok()?;
+    match value.trim().parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("Ignoring invalid value {:?} for {}", value, key);
+            None
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use http::Method;
+use librespot_core::{Error, Session, SpotifyId};
+use log::debug;
+use serde::Deserialize;
+
+// Play history of the Spotify clients. It lists the contexts played from, each with the last
+// track played in it, most recent first.
+const RECENTLY_PLAYED_ENDPOINT: &str = "/recently-played/v3/user";
+const PAGE_SIZE: usize = 50;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryPage {
+    #[serde(default)]
+    play_contexts: Vec<PlayContext>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayContext {
+    #[serde(default)]
+    last_played_track_uri: String,
+}
+
+/// List the tracks and episodes recently played by the user, most recent first. Only the pages
+/// needed to list the first `limit` items are fetched, when given.
+pub async fn recently_played(
+    session: &Session,
+    limit: Option<usize>,
+) -> Result<Vec<SpotifyId>, Error> {
+    let username =
+        url::form_urlencoded::byte_serialize(session.username().as_bytes()).collect::<String>();
+    let mut items: Vec<SpotifyId> = vec![];
+    let mut offset = 0;
+    loop {
+        let endpoint = format!(
+            "{}/{}/recently-played?format=json&offset={}&limit={}&filter=default,collection-new-episodes",
+            RECENTLY_PLAYED_ENDPOINT, username, offset, PAGE_SIZE
+        );
+        let response = session
+            .spclient()
+            .request(&Method::GET, &endpoint, None, None)
+            .await?;
+        let page: HistoryPage = serde_json::from_slice(&response)
+            .map_err(|e| Error::failed_precondition(format!("invalid play history: {}", e)))?;
+
+        let count = page.play_contexts.len();
+        for context in page.play_contexts {
+            // Contexts played from before any track started have none.
+            if context.last_played_track_uri.is_empty() {
+                continue;
+            }
+            match SpotifyId::from_uri(&context.last_played_track_uri) {
+                // The same track may be the last played of several contexts.
+                Ok(id) if !items.contains(&id) => items.push(id),
+                Ok(_) => {}
+                Err(e) => debug!(
+                    "Ignoring history item {}: {}",
+                    context.last_played_track_uri, e
+                ),
+            }
+        }
+        if count < PAGE_SIZE || limit.is_some_and(|limit| items.len() >= limit) {
+            return Ok(items);
+        }
+        offset += count;
+    }
+}
@@ -1,19 +1,23 @@
 #![feature(cursor_remaining)]
 use std::io::{Read, Seek, SeekFrom};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use librespot_audio::AudioFetchParams;
-use librespot_metadata::audio::AudioFileFormat;
-use librespot_metadata::{Metadata, Rootlist};
+use librespot_metadata::artist::ArtistRole;
+use librespot_metadata::audio::{AudioFileFormat, AudioFiles};
+use librespot_metadata::image::{Image, ImageSize};
+use librespot_metadata::{Album, Metadata, Rootlist};
 use librespot_playback::config::{Bitrate, PlayerConfig};
 use librespot_playback::mixer::NoOpVolume;
 use librespot_playback::player::Player;
 
+use futures_util::{future, stream, StreamExt};
 use librespot_core::cache::Cache;
 use librespot_core::{
     config::SessionConfig,
     session::Session,
     spotify_id::{SpotifyId, SpotifyItemType},
+    FileId,
 };
 use librespot_playback::{
     audio_backend::{Sink, SinkResult},
@@ -22,17 +26,24 @@ use librespot_playback::{
 };
 use log::{debug, error, info, warn};
 use pb::{
-    CloseRequest, CloseResponse, FetchContentRequest, OpenRequest, OpenResponse, SeekRequest,
-    SeekResponse, Track, TrackRequest, TrackResponse,
+    AppendToPlaylistRequest, AppendToPlaylistResponse, Artist, Artwork, ArtworkBatchRequest,
+    ArtworkBatchResponse, ArtworkRequest, ArtworkResponse, CloseManyRequest, CloseManyResponse,
+    CloseRequest, CloseResponse, ClosedTrack, FetchContentRequest, OpenRequest, OpenResponse,
+    PlayFirstMatchRequest, PreloadRequest, PreloadResponse, PrewarmKeysRequest,
+    PrewarmKeysResponse, SeekRequest, SeekResponse, ShowRequest, ShowResponse, Track,
+    TrackBatchItem, TrackBatchRequest, TrackRequest, TrackResponse, TracklistDurationRequest,
+    TracklistDurationResponse,
 };
 use std::cmp;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::vec;
 use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc::{self};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 use tokio_stream::wrappers::{ReceiverStream, UnixListenerStream};
 use tokio_stream::Stream;
 use tonic::transport::server::UdsConnectInfo;
@@ -48,27 +59,123 @@ use pb::{
     track_service_server::{TrackService, TrackServiceServer},
     tracklist_service_server::{TracklistService, TracklistServiceServer},
     view_event::ViewEventOneof,
-    BrowseReply, BrowseRequest, ManifestReply, ManifestRequest, Node, NodeType, ReadChunk,
-    ReadRequest, SideEffect, ViewEvent,
+    AccountReply, AccountRequest, BrowseReply, BrowseRequest, CacheStatusReply, CacheStatusRequest,
+    CancelReply, CancelRequest, ConnectionState, ConnectionStatusReply, ConnectionStatusRequest,
+    EvictCacheReply, EvictCacheRequest, ListOpenedRequest, ListOpenedResponse, LogoutReply,
+    LogoutRequest, ManifestReply, ManifestRequest, Node, NodeType, OpenedTrackInfo, ReadChunk,
+    ReadEnd, ReadRequest, SearchRequest, SearchResult, SearchType, SideEffect, TestLoginReply,
+    TestLoginRequest, VerifyCacheReply, VerifyCacheRequest, ViewEvent,
 };
 
+mod artwork;
 mod audio;
+mod cancel;
+mod collection;
+mod config;
+mod history;
+mod metadata;
+mod search;
+mod storage;
 mod view;
 
-use audio::loader::TrackLoader;
+use artwork::ArtworkCache;
+use audio::error::LoaderError;
+use audio::loader::{OpenOptions, TrackLoader};
+use audio::offline::OfflineIndex;
+use cancel::{CancelRegistry, StreamHandle};
+use config::{Config, FormatConflict};
 use view::login::{get_qml_view, LoginForm};
 
+use crate::metadata::{TracklistItem, TracklistRef};
+use crate::pb::search_result::SearchResultOneof;
 use crate::pb::{SearchMode, Tracklist};
+use crate::search::SearchKind;
 
-#[derive(Clone, Default)]
+// Name given by librespot to the credentials file within its cache.
+const CREDENTIALS_FILE: &str = "credentials.json";
+
+const DJ_PLAYLIST_URI: &str = "spotify:playlist:37i9dQZF1EYkqdzj48dyYq";
+
+// Track available in every market, whose audio key is requested to check playback.
+const PLAYBACK_CHECK_TRACK_URI: &str = "spotify:track:4uLU6hMCjMI75M1A2tKUQC";
+const PLAYBACK_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Spotify serves the 30 seconds previews as plain MP3 from its CDN.
+const PREVIEW_URL_BASE: &str = "https://p.scdn.co/mp3-preview/";
+
+// How often to check whether the session is still alive.
+const SESSION_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Smallest chunk served by `read`. The largest one is the fetch buffer size, as bigger chunks
+// would be read through several network chunks anyway.
+const MIN_READ_CHUNK_SIZE: usize = 128;
+
+// Per-chunk read latencies driving the adaptive chunk size in `read`.
+const ADAPTIVE_CHUNK_FAST: Duration = Duration::from_millis(5);
+const ADAPTIVE_CHUNK_SLOW: Duration = Duration::from_millis(50);
+
+// Bounds on the metadata lookups done to sum up a tracklist duration.
+const MAX_DURATION_TRACKS: usize = 1_000;
+// Metadata lookups in flight at once when listing albums or tracklists.
+const METADATA_CONCURRENCY: usize = 8;
+
+// Response metadata holding the id of a `read` or `fetch_content` stream.
+const STREAM_ID_HEADER: &str = "x-stream-id";
+
+// Upper bound on the results returned by a single `search`.
+const MAX_SEARCH_RESULTS: usize = 50;
+
+// Maximum number of tracks whose audio key is requested by `prewarm_keys`.
+const MAX_PREWARM_TRACKS: usize = 500;
+
+// Number of tracks following an opened one in its tracklist which get preloaded.
+const PRELOAD_AHEAD: usize = 2;
+
+// Number of images kept in memory once fetched.
+const ARTWORK_CACHE_SIZE: usize = 512;
+// Bounds on the images requested with `get_artwork_batch`.
+const MAX_ARTWORK_BATCH: usize = 100;
+const ARTWORK_CONCURRENCY: usize = 8;
+
+// Upper bound on the header bytes returned by `open` for format sniffing.
+const MAX_PEEK_SIZE: usize = 65_536;
+
+// Delay before retrying to bind the socket, growing with each attempt.
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+// Pause after a failed accept, so a persistent error (e.g. too many open files) doesn't spin.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
 pub struct Plugin {
     state: Arc<Mutex<PluginState>>,
+    config: Arc<Config>,
+    last_activity: Arc<std::sync::Mutex<Instant>>,
+    read_streams: Arc<Semaphore>,
+    artwork: Arc<std::sync::Mutex<ArtworkCache>>,
+    streams: Arc<CancelRegistry>,
 }
 
 enum SessionStatus {
     Disconnect,
     Failed(String),
-    Connected(Box<Rootlist>),
+    /// The rootlist is `None` until it could be fetched, which doesn't prevent using the session.
+    Connected(Option<Box<Rootlist>>),
+}
+
+/// Fetch the user's rootlist. Failing to is only logged, as the session remains usable.
+async fn fetch_rootlist(session: &Session) -> Option<Box<Rootlist>> {
+    let root = SpotifyId {
+        id: 0,
+        item_type: SpotifyItemType::Unknown,
+    };
+    match Rootlist::get(session, &root).await {
+        Ok(rootlist) => Some(Box::new(rootlist)),
+        Err(e) => {
+            warn!("Cannot fetch rootlist, retrying on the next browse: {}", e);
+            None
+        }
+    }
 }
 
 struct PluginState {
@@ -76,38 +183,150 @@ struct PluginState {
     status: SessionStatus,
     loader: Arc<tokio::sync::Mutex<TrackLoader>>,
     player: Arc<Player>,
+    config: Arc<Config>,
+    /// Names of the albums, artists and shows of the library resolved so far, for the library
+    /// nodes to be paged without fetching their metadata again.
+    library_names: Arc<std::sync::Mutex<HashMap<SpotifyId, String>>>,
+    /// Set once disconnected for being idle, for the next request to reconnect.
+    idle: bool,
 }
 
-impl Default for PluginState {
-    fn default() -> Self {
-        let config = SessionConfig::default();
-        // config.proxy = Some(Url::parse("http://127.0.0.1:8080").unwrap());
-        let session = Session::new(
-            config,
-            Cache::new(
-                "./spotcache".into(),
-                None,
-                "./spotcache".into(),
-                Some(1_000_000_000),
-            )
-            .ok(),
-        );
+impl PluginState {
+    fn new(config: Arc<Config>) -> Self {
+        let session = Self::new_session(&config);
+        // Offline playback relies on the audio files of the cache.
+        let offline = session
+            .cache()
+            .and(config.cache_dir.as_ref())
+            .and_then(|dir| OfflineIndex::new(dir.join("offline")));
 
         PluginState {
-            loader: Arc::new(tokio::sync::Mutex::new(TrackLoader::new(session.clone()))),
+            loader: Arc::new(tokio::sync::Mutex::new(
+                TrackLoader::new(session.clone(), offline)
+                    .with_open_retries(config.open_retries)
+                    .with_fetch_buffer_size(config.fetch_buffer_size)
+                    .with_max_preloaded(config.max_idle_tracks)
+                    .with_prefer_cached(config.prefer_cached_alternative)
+                    .with_format_preference(config.format_preference())
+                    .with_market(config.market.clone()),
+            )),
             status: SessionStatus::Disconnect,
-            player: Player::new(
-                PlayerConfig {
-                    bitrate: Bitrate::Bitrate320,
-                    ..PlayerConfig::default()
-                },
-                session.clone(),
-                Box::new(NoOpVolume),
-                move || Box::new(EmptySink {}),
-            ),
+            player: Self::new_player(&config, &session),
             session,
+            config,
+            library_names: Arc::default(),
+            idle: false,
+        }
+    }
+    fn new_session(config: &Config) -> Session {
+        Session::new(Self::session_config(config), Self::new_cache(config))
+    }
+    fn session_config(config: &Config) -> SessionConfig {
+        SessionConfig {
+            // Validated on startup.
+            proxy: config.proxy_url().ok().flatten(),
+            ..SessionConfig::default()
+        }
+    }
+    /// Open the configured cache, or run without any if it can't be stored where configured.
+    fn new_cache(config: &Config) -> Option<Cache> {
+        let dir = config.cache_dir.as_ref()?;
+        if let Err(e) = storage::check_writable(dir) {
+            warn!("Caching disabled, cannot write to {:?}: {}", dir, e);
+            return None;
+        }
+        Cache::new(
+            config.credentials_dir.as_ref(),
+            config.volume_dir.as_ref(),
+            Some(dir),
+            config.cache_size_limit,
+        )
+        .map_err(|e| {
+            warn!(
+                "Caching disabled, cannot open the cache in {:?}: {}",
+                dir, e
+            )
+        })
+        .ok()
+    }
+    fn new_player(config: &Config, session: &Session) -> Arc<Player> {
+        Player::new(
+            PlayerConfig {
+                bitrate: config.bitrate,
+                ..PlayerConfig::default()
+            },
+            session.clone(),
+            Box::new(NoOpVolume),
+            move || Box::new(EmptySink {}),
+        )
+    }
+    /// Replace the session by a new one logged in with the cached credentials, keeping the
+    /// opened tracks.
+    async fn reconnect(&mut self) -> Result<(), librespot_core::Error> {
+        let credentials = self
+            .session
+            .cache()
+            .and_then(|cache| cache.credentials())
+            .ok_or_else(|| librespot_core::Error::unauthenticated("no cached credentials"))?;
+        let session = Self::new_session(&self.config);
+        session.connect(credentials, true).await?;
+        let rootlist = fetch_rootlist(&session).await;
+
+        self.loader.lock().await.set_session(session.clone());
+        self.player = Self::new_player(&self.config, &session);
+        self.session.shutdown();
+        self.session = session;
+        self.status = SessionStatus::Connected(rootlist);
+        Ok(())
+    }
+    /// Fetch the rootlist if it couldn't be when connecting.
+    async fn ensure_rootlist(&mut self) {
+        if let SessionStatus::Connected(rootlist @ None) = &mut self.status {
+            *rootlist = fetch_rootlist(&self.session).await;
+        }
+    }
+    /// Reconnect if Spotify closed the session, e.g. once its token expired, or if it was
+    /// disconnected for being idle. The session is marked as failed if that doesn't work, for
+    /// the user to log in again, except after being idle where it stays disconnected.
+    async fn revive(&mut self) {
+        if self.idle {
+            self.idle = false;
+            match self.reconnect().await {
+                Ok(()) => info!(
+                    "Reconnected as {} after being idle",
+                    self.session.username()
+                ),
+                Err(e) => warn!("Unable to reconnect after being idle: {}", e),
+            }
+            return;
+        }
+        if !matches!(self.status, SessionStatus::Connected(_)) || !self.session.is_invalid() {
+            return;
+        }
+        warn!(
+            "Session for {} was closed by Spotify, reconnecting",
+            self.session.username()
+        );
+        match self.reconnect().await {
+            Ok(()) => info!("Reconnected as {}", self.session.username()),
+            Err(e) => {
+                error!("Unable to reconnect: {}", e);
+                self.status = SessionStatus::Failed(format!("Connection to Spotify lost: {}", e));
+            }
         }
     }
+    /// Market to resolve tracks in when the client doesn't ask for one.
+    fn market(&self) -> String {
+        self.config
+            .market
+            .clone()
+            .unwrap_or_else(|| self.session.country())
+    }
+    /// Drop the current session and start over with a fresh, disconnected one.
+    fn reset(&mut self) {
+        self.session.shutdown();
+        *self = PluginState::new(Arc::clone(&self.config));
+    }
 }
 
 #[derive(PartialEq)]
@@ -115,6 +334,13 @@ pub enum PlaylistType {
     ForYou,
     Followed,
     MyOwn,
+    Dj,
+    SavedEpisodes,
+    LikedSongs,
+    RecentlyPlayed,
+    SavedAlbums,
+    FollowedArtists,
+    Podcasts,
 }
 
 impl TryFrom<std::string::String> for PlaylistType {
@@ -126,14 +352,100 @@ impl TryFrom<std::string::String> for PlaylistType {
             "followedplaylist" => Ok(PlaylistType::Followed),
             "myplaylist" => Ok(PlaylistType::MyOwn),
             "foryou" => Ok(PlaylistType::ForYou),
+            "dj" => Ok(PlaylistType::Dj),
+            "savedepisodes" => Ok(PlaylistType::SavedEpisodes),
+            "likedsongs" => Ok(PlaylistType::LikedSongs),
+            "recent" => Ok(PlaylistType::RecentlyPlayed),
+            "savedalbums" => Ok(PlaylistType::SavedAlbums),
+            "followedartists" => Ok(PlaylistType::FollowedArtists),
+            "podcasts" => Ok(PlaylistType::Podcasts),
             _ => Err("Unknown playlist type"),
         }
     }
 }
 
 impl Plugin {
-    async fn get_root_node(&self) -> Result<BrowseReply, Status> {
+    pub fn new(config: Config) -> Self {
+        let config = Arc::new(config);
+        Self {
+            state: Arc::new(Mutex::new(PluginState::new(Arc::clone(&config)))),
+            read_streams: Arc::new(Semaphore::new(config.max_read_streams)),
+            config,
+            last_activity: Arc::new(std::sync::Mutex::new(Instant::now())),
+            artwork: Arc::new(std::sync::Mutex::new(ArtworkCache::new(ARTWORK_CACHE_SIZE))),
+            streams: Arc::default(),
+        }
+    }
+    /// Directory of the cache, for the RPCs managing it.
+    fn cache_dir(&self) -> Result<PathBuf, Status> {
+        self.config
+            .cache_dir
+            .clone()
+            .ok_or_else(|| Status::new(Code::FailedPrecondition, "caching is disabled"))
+    }
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+    fn register_stream(&self, id: String) -> Result<StreamHandle, Status> {
+        self.streams.register(id).ok_or_else(|| {
+            Status::new(
+                Code::AlreadyExists,
+                "a stream with this id is already running",
+            )
+        })
+    }
+    /// Disconnect the session once no RPC was received for `timeout`, no track is open and no
+    /// stream is running. The next request reconnects it from the cached credentials.
+    async fn watch_idle(&self, timeout: Duration) {
+        loop {
+            let idle = self.last_activity.lock().unwrap().elapsed();
+            if idle < timeout {
+                tokio::time::sleep(cmp::min(timeout - idle, Duration::from_secs(60))).await;
+                continue;
+            }
+
+            let mut state = self.state.lock().await;
+            if matches!(state.status, SessionStatus::Connected(_))
+                && self.streams.is_empty()
+                && state.loader.lock().await.is_empty()
+            {
+                info!("Disconnecting after being idle for {:?}", idle);
+                let dropped_preloads = state.loader.lock().await.close_all();
+                debug!("Dropped {} preloaded tracks", dropped_preloads);
+                state.reset();
+                state.idle = true;
+            }
+            drop(state);
+            self.touch();
+        }
+    }
+    /// Stop the running streams and close the opened tracks, before the plugin exits.
+    async fn shutdown(&self) {
+        let cancelled_streams = self.streams.cancel_all();
         let state = self.state.lock().await;
+        let closed_tracks = state.loader.lock().await.close_all();
+        state.session.shutdown();
+        info!(
+            "Cancelled {} streams and closed {} tracks",
+            cancelled_streams, closed_tracks
+        );
+    }
+    /// librespot doesn't expose its session events, so poll the session for
+    /// invalidation (e.g. connection dropped by Spotify) and reconnect it.
+    async fn watch_session(&self) {
+        loop {
+            tokio::time::sleep(SESSION_POLL_INTERVAL).await;
+            let mut state = self.state.lock().await;
+            // Sessions disconnected for being idle are only reconnected on request.
+            if !state.idle {
+                state.revive().await;
+            }
+        }
+    }
+    async fn get_root_node(&self) -> Result<BrowseReply, Status> {
+        let mut state = self.state.lock().await;
+        state.revive().await;
+        state.ensure_rootlist().await;
         match &state.status {
             SessionStatus::Connected(profile) => {
                 info!("{:?}", profile);
@@ -144,107 +456,520 @@ impl Plugin {
                             label: "Followed playlists".to_owned(),
                             id: "/followedplaylist/".to_owned(),
                             icon: vec![],
+                            base62_id: String::new(),
                         },
                         Node {
                             r#type: NodeType::Node.into(),
                             label: "My Playlists".to_owned(),
                             id: "/myplaylist/".to_owned(),
                             icon: vec![],
+                            base62_id: String::new(),
                         },
                         Node {
                             r#type: NodeType::Node.into(),
                             label: "For you".to_owned(),
                             id: "/foryou/".to_owned(),
                             icon: vec![],
+                            base62_id: String::new(),
+                        },
+                        Node {
+                            r#type: NodeType::Node.into(),
+                            label: "Saved albums".to_owned(),
+                            id: "/savedalbums/".to_owned(),
+                            icon: vec![],
+                            base62_id: String::new(),
+                        },
+                        Node {
+                            r#type: NodeType::Node.into(),
+                            label: "Followed artists".to_owned(),
+                            id: "/followedartists/".to_owned(),
+                            icon: vec![],
+                            base62_id: String::new(),
+                        },
+                        Node {
+                            r#type: NodeType::Node.into(),
+                            label: "Podcasts".to_owned(),
+                            id: "/podcasts/".to_owned(),
+                            icon: vec![],
+                            base62_id: String::new(),
+                        },
+                        Node {
+                            r#type: NodeType::Leaf.into(),
+                            label: "DJ".to_owned(),
+                            id: "/dj/".to_owned(),
+                            icon: vec![],
+                            base62_id: String::new(),
+                        },
+                        Node {
+                            r#type: NodeType::Leaf.into(),
+                            label: "Recently played".to_owned(),
+                            id: metadata::RECENTLY_PLAYED_REF.to_owned(),
+                            icon: vec![],
+                            base62_id: String::new(),
+                        },
+                        Node {
+                            r#type: NodeType::Leaf.into(),
+                            label: "Liked Songs".to_owned(),
+                            id: metadata::LIKED_SONGS_REF.to_owned(),
+                            icon: vec![],
+                            base62_id: String::new(),
+                        },
+                        Node {
+                            r#type: NodeType::Leaf.into(),
+                            label: "Your Episodes".to_owned(),
+                            id: metadata::SAVED_EPISODES_REF.to_owned(),
+                            icon: vec![],
+                            base62_id: String::new(),
                         },
                     ],
                     ..BrowseReply::default()
                 })
             }
             SessionStatus::Disconnect | SessionStatus::Failed(_) => Ok(BrowseReply {
-                view: get_qml_view().map_err(|e| {
+                view: get_qml_view(&self.config.resource_dir).map_err(|e| {
                     error!("Unable to open root view: {}", e);
-                    Status::new(Code::Unimplemented, "Unable to fetch root view")
+                    Status::new(
+                        Code::Unimplemented,
+                        format!("Unable to fetch root view: {}", e),
+                    )
                 })?,
                 ..BrowseReply::default()
             }),
         }
     }
+    /// List the folders and playlists of the user's library found at `folder_path`, a list of
+    /// folder ids from the top level. `node_id` is the id of the node being browsed, under which
+    /// the folders get their own ids.
     async fn get_playlist_node(
         &self,
         state: &PluginState,
-        rootlist: &Rootlist,
         playlist_type: PlaylistType,
+        node_id: &str,
+        folder_path: &[&str],
     ) -> Result<BrowseReply, Status> {
-        let mut playlists: Vec<(String, SpotifyId)> = rootlist
-            .contents
-            .meta_items
-            .iter()
-            .zip(rootlist.contents.items.iter())
-            .filter_map(|(meta, item)| match meta.owner_username.as_str() {
-                "spotify" if playlist_type == PlaylistType::ForYou => {
-                    Some((meta.attributes.name.to_owned(), item.id))
-                }
-                me if me == state.session.username() && playlist_type == PlaylistType::MyOwn => {
-                    Some((meta.attributes.name.to_owned(), item.id))
-                }
-                _ if playlist_type == PlaylistType::Followed => {
-                    Some((meta.attributes.name.to_owned(), item.id))
+        let library = metadata::library_playlists(&state.session)
+            .await
+            .map_err(|e| {
+                Status::new(
+                    Code::Unavailable,
+                    format!("unable to get playlists: {:}", e),
+                )
+            })?;
+        let username = state.session.username();
+
+        let mut folders: Vec<&metadata::PlaylistFolder> = vec![];
+        let mut playlists: Vec<(String, SpotifyId, Option<FileId>)> = vec![];
+        for playlist in library.iter() {
+            let listed = match playlist_type {
+                PlaylistType::ForYou => playlist.owner == "spotify",
+                PlaylistType::MyOwn => playlist.owner == username,
+                PlaylistType::Followed => true,
+                _ => false,
+            };
+            let in_folder = playlist.folders.len() >= folder_path.len()
+                && playlist
+                    .folders
+                    .iter()
+                    .zip(folder_path)
+                    .all(|(folder, id)| folder.id == *id);
+            if !listed || !in_folder {
+                continue;
+            }
+            // Playlists nested deeper show up through the subfolder containing them.
+            match playlist.folders.get(folder_path.len()) {
+                None => {
+                    playlists.push((display_name(&playlist.name), playlist.id, playlist.picture))
                 }
-                _ => None,
-            })
-            .collect();
+                Some(folder) if !folders.iter().any(|f| f.id == folder.id) => folders.push(folder),
+                Some(_) => {}
+            }
+        }
+        folders.sort_by_key(|folder| folder.name.to_owned());
         playlists.sort_by_key(|i| i.0.to_owned());
+
+        // A missing cover only leaves the playlist with the default icon.
+        let session = &state.session;
+        let artwork = &self.artwork;
+        let icons: Vec<Vec<u8>> = stream::iter(playlists.iter())
+            .map(|p| async move {
+                let Some(picture) = p.2 else {
+                    return vec![];
+                };
+                artwork::fetch_artwork(session, artwork, picture)
+                    .await
+                    .unwrap_or_else(|e| {
+                        debug!("No icon for {}: {}", p.1, e);
+                        vec![]
+                    })
+            })
+            .buffered(ARTWORK_CONCURRENCY)
+            .collect()
+            .await;
+
+        let node_id = node_id.trim_end_matches('/');
         Ok(BrowseReply {
-            nodes: playlists
+            nodes: folders
                 .iter()
-                .map(|p| Node {
+                .map(|folder| Node {
+                    r#type: NodeType::Node.into(),
+                    label: display_name(&folder.name),
+                    id: format!("{}/{}/", node_id, folder.id),
+                    icon: vec![],
+                    base62_id: String::new(),
+                })
+                .chain(playlists.iter().zip(icons).map(|(p, icon)| Node {
                     r#type: NodeType::Leaf.into(),
                     label: p.0.to_owned(),
                     id: p.1.to_string(),
-                    icon: vec![],
-                })
+                    icon,
+                    base62_id: base62_id(&p.1),
+                }))
                 .collect(),
             tracklist: Option::None,
             view: "".into(),
         })
     }
-    async fn get_node(&self, node: &Node) -> Result<BrowseReply, Status> {
-        let state = self.state.lock().await;
+    /// The DJ is exposed by Spotify as a personalised playlist, which only exists for the
+    /// accounts and markets where the feature is rolled out.
+    async fn get_dj_node(&self, state: &PluginState) -> Result<BrowseReply, Status> {
+        let dj_uri = SpotifyId::from_uri(DJ_PLAYLIST_URI)
+            .map_err(|e| Status::new(Code::Internal, e.to_string()))?;
+        let plist = librespot_metadata::Playlist::get(&state.session, &dj_uri)
+            .await
+            .map_err(|e| {
+                warn!("Unable to get the DJ playlist: {}", e);
+                Status::new(
+                    Code::Unavailable,
+                    "Spotify DJ is not available for this account",
+                )
+            })?;
+
+        Ok(BrowseReply {
+            tracklist: Some(Tracklist {
+                r#ref: DJ_PLAYLIST_URI.to_owned(),
+                id: dj_uri.id as i64,
+                search: SearchMode::None.into(),
+                track_count: plist.length,
+                collaborative: plist.attributes.is_collaborative,
+                ..Tracklist::default()
+            }),
+            ..BrowseReply::default()
+        })
+    }
+    /// Only the ids of the saved episodes are listed, for their count.
+    async fn get_saved_episodes_node(&self, session: &Session) -> Result<BrowseReply, Status> {
+        let episodes = collection::collection_items(
+            session,
+            collection::SAVED_EPISODES,
+            SpotifyItemType::Episode,
+            None,
+        )
+        .await
+        .map_err(|e| {
+            Status::new(
+                Code::Unavailable,
+                format!("unable to get saved episodes: {:}", e),
+            )
+        })?;
+        Ok(BrowseReply {
+            tracklist: Some(Tracklist {
+                r#ref: metadata::SAVED_EPISODES_REF.to_owned(),
+                search: SearchMode::None.into(),
+                track_count: episodes.len() as i32,
+                ..Tracklist::default()
+            }),
+            ..BrowseReply::default()
+        })
+    }
+    /// Only the ids of the liked songs are listed, for their count.
+    async fn get_liked_songs_node(&self, session: &Session) -> Result<BrowseReply, Status> {
+        let tracks = collection::collection_items(
+            session,
+            collection::LIKED_SONGS,
+            SpotifyItemType::Track,
+            None,
+        )
+        .await
+        .map_err(|e| {
+            Status::new(
+                Code::Unavailable,
+                format!("unable to get liked songs: {:}", e),
+            )
+        })?;
+        Ok(BrowseReply {
+            tracklist: Some(Tracklist {
+                r#ref: metadata::LIKED_SONGS_REF.to_owned(),
+                search: SearchMode::None.into(),
+                track_count: tracks.len() as i32,
+                ..Tracklist::default()
+            }),
+            ..BrowseReply::default()
+        })
+    }
+    /// The play history changes all the time, so its track count is only a snapshot. Only the
+    /// ids of the tracks are listed, for their count.
+    async fn get_recently_played_node(&self, session: &Session) -> Result<BrowseReply, Status> {
+        let tracks = history::recently_played(session, None).await.map_err(|e| {
+            Status::new(
+                Code::Unavailable,
+                format!("unable to get recently played tracks: {:}", e),
+            )
+        })?;
+        Ok(BrowseReply {
+            tracklist: Some(Tracklist {
+                r#ref: metadata::RECENTLY_PLAYED_REF.to_owned(),
+                search: SearchMode::None.into(),
+                track_count: tracks.len() as i32,
+                ..Tracklist::default()
+            }),
+            ..BrowseReply::default()
+        })
+    }
+    /// List the albums saved, or the artists or shows followed by the user, sorted by name.
+    /// Albums and shows open into their tracklist, and artists into their own node.
+    /// The names are kept in `names` once resolved, so that only the first browse of the node
+    /// fetches the metadata of every item, as needed to sort them.
+    async fn get_library_node(
+        &self,
+        session: &Session,
+        names: &std::sync::Mutex<HashMap<SpotifyId, String>>,
+        playlist_type: PlaylistType,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<BrowseReply, Status> {
+        let (set, item_type) = match playlist_type {
+            PlaylistType::SavedAlbums => (collection::SAVED_ALBUMS, SpotifyItemType::Album),
+            PlaylistType::Podcasts => (collection::FOLLOWED_SHOWS, SpotifyItemType::Show),
+            _ => (collection::FOLLOWED_ARTISTS, SpotifyItemType::Artist),
+        };
+        let ids = collection::collection_items(session, set, item_type, None)
+            .await
+            .map_err(|e| {
+                Status::new(
+                    Code::Unavailable,
+                    format!("unable to get the library: {:}", e),
+                )
+            })?;
+
+        let unnamed: Vec<SpotifyId> = {
+            let names = names.lock().unwrap();
+            ids.iter()
+                .filter(|id| !names.contains_key(id))
+                .copied()
+                .collect()
+        };
+        let resolved: Vec<(SpotifyId, String)> = stream::iter(unnamed)
+            .map(|id| async move {
+                let name = match item_type {
+                    SpotifyItemType::Album => Album::get(session, &id).await.map(|a| a.name),
+                    SpotifyItemType::Show => librespot_metadata::Show::get(session, &id)
+                        .await
+                        .map(|show| show.name),
+                    _ => librespot_metadata::Artist::get(session, &id)
+                        .await
+                        .map(|artist| artist.name),
+                };
+                (id, name)
+            })
+            .buffered(METADATA_CONCURRENCY)
+            .filter_map(|(id, name)| {
+                future::ready(match name {
+                    Ok(name) => Some((id, display_name(&name))),
+                    Err(e) => {
+                        warn!("Leaving {} out of the library: {}", id, e);
+                        None
+                    }
+                })
+            })
+            .collect()
+            .await;
+
+        let mut items: Vec<(String, SpotifyId)> = {
+            let mut names = names.lock().unwrap();
+            names.extend(resolved);
+            ids.into_iter()
+                .filter_map(|id| names.get(&id).map(|name| (name.clone(), id)))
+                .collect()
+        };
+        items.sort_by_key(|i| i.0.to_lowercase());
+
+        let node_type = match item_type {
+            SpotifyItemType::Artist => NodeType::Node,
+            _ => NodeType::Leaf,
+        };
+        Ok(BrowseReply {
+            nodes: items
+                .into_iter()
+                .skip(offset)
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|(label, id)| Node {
+                    r#type: node_type.into(),
+                    label,
+                    id: id.to_string(),
+                    icon: vec![],
+                    base62_id: base62_id(&id),
+                })
+                .collect(),
+            ..BrowseReply::default()
+        })
+    }
+    async fn get_artist_node(
+        &self,
+        state: &PluginState,
+        artist_uri: &SpotifyId,
+    ) -> Result<BrowseReply, Status> {
+        let artist = librespot_metadata::Artist::get(&state.session, artist_uri)
+            .await
+            .map_err(|e| Status::new(Code::Unavailable, format!("unable to get artist: {:}", e)))?;
+
+        let albums: Vec<Album> = stream::iter(artist.albums_current())
+            .map(|album_id| Album::get(&state.session, album_id))
+            .buffered(METADATA_CONCURRENCY)
+            .filter_map(|album| future::ready(album.ok()))
+            .collect()
+            .await;
+
+        Ok(BrowseReply {
+            nodes: albums
+                .iter()
+                .map(|album| Node {
+                    r#type: NodeType::Leaf.into(),
+                    label: display_name(&album.name),
+                    id: album.id.to_string(),
+                    icon: vec![],
+                    base62_id: base62_id(&album.id),
+                })
+                .collect(),
+            tracklist: Some(Tracklist {
+                r#ref: artist_uri.to_string(),
+                id: artist_uri.id as i64,
+                search: SearchMode::None.into(),
+                track_count: artist.top_tracks.for_country(&state.market()).len() as i32,
+                ..Tracklist::default()
+            }),
+            ..BrowseReply::default()
+        })
+    }
+    async fn get_node(
+        &self,
+        node: &Node,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<BrowseReply, Status> {
+        let mut state = self.state.lock().await;
+        state.revive().await;
+        state.ensure_rootlist().await;
         match &state.status {
             SessionStatus::Connected(rootlist) => {
-                if node.id.starts_with("spotify:playlist") {
-                    let plist_uri = SpotifyId::from_uri(&node.id).map_err(|e| {
+                if node.id.starts_with("spotify:") {
+                    let uri = metadata::parse_uri(&node.id).map_err(|e| {
                         Status::new(
                             Code::InvalidArgument,
-                            format!("Couldn't parse the playlist id: {:}", e),
+                            format!("Couldn't parse the node id: {:}", e),
                         )
                     })?;
 
-                    let plist = librespot_metadata::Playlist::get(&state.session, &plist_uri)
-                        .await
-                        .unwrap();
-                    info!("{:?}", plist);
+                    let mut tracklist = Tracklist {
+                        r#ref: node.id.to_owned(),
+                        id: uri.id as i64,
+                        search: SearchMode::None.into(),
+                        ..Tracklist::default()
+                    };
+                    tracklist.track_count = match uri.item_type {
+                        SpotifyItemType::Artist => {
+                            return self.get_artist_node(&state, &uri).await;
+                        }
+                        SpotifyItemType::Playlist => {
+                            let plist = librespot_metadata::Playlist::get(&state.session, &uri)
+                                .await
+                                .map_err(|e| {
+                                    Status::new(
+                                        Code::Unavailable,
+                                        format!("unable to get playlist: {:}", e),
+                                    )
+                                })?;
+                            info!("{:?}", plist);
+                            tracklist.collaborative = plist.attributes.is_collaborative;
+                            // Only known for the playlists in the user's library.
+                            tracklist.public = rootlist.as_ref().is_some_and(|rootlist| {
+                                rootlist
+                                    .contents
+                                    .items
+                                    .iter()
+                                    .find(|item| item.id == uri)
+                                    .is_some_and(|item| item.attributes.is_public)
+                            });
+                            plist.length
+                        }
+                        SpotifyItemType::Album | SpotifyItemType::Show => {
+                            metadata::tracklist_items(
+                                &state.session,
+                                &TracklistRef::Item(uri),
+                                &state.market(),
+                            )
+                            .await
+                            .map_err(|e| {
+                                Status::new(
+                                    Code::Unavailable,
+                                    format!("unable to get tracklist: {:}", e),
+                                )
+                            })?
+                            .len() as i32
+                        }
+                        _ => {
+                            return Err(Status::new(
+                                Code::Unimplemented,
+                                format!("Cannot browse {:}", node.id),
+                            ))
+                        }
+                    };
 
                     Ok(BrowseReply {
                         nodes: vec![],
-                        tracklist: Some(Tracklist {
-                            r#ref: node.id.to_owned(),
-                            id: plist_uri.id as i64,
-                            search: SearchMode::None.into(),
-                            track_count: plist.length,
-                        }),
+                        tracklist: Some(tracklist),
                         view: "".into(),
                     })
                 } else {
-                    self.get_playlist_node(
-                        &state,
-                        rootlist,
-                        node.id.clone().try_into().map_err(|_| {
-                            Status::new(Code::Unimplemented, "Unrecognised node type")
-                        })?,
-                    )
-                    .await
+                    // Folders of playlists are browsed as `/<playlist type>/<folder id>/...`.
+                    let mut segments = node.id.split('/').filter(|s| !s.is_empty());
+                    let playlist_type: PlaylistType = segments
+                        .next()
+                        .unwrap_or_default()
+                        .to_owned()
+                        .try_into()
+                        .map_err(|_| Status::new(Code::Unimplemented, "Unrecognised node type"))?;
+                    let folder_path: Vec<&str> = segments.collect();
+                    match playlist_type {
+                        PlaylistType::Dj => self.get_dj_node(&state).await,
+                        PlaylistType::SavedEpisodes => {
+                            let session = state.session.clone();
+                            drop(state);
+                            self.get_saved_episodes_node(&session).await
+                        }
+                        PlaylistType::LikedSongs => {
+                            let session = state.session.clone();
+                            drop(state);
+                            self.get_liked_songs_node(&session).await
+                        }
+                        PlaylistType::RecentlyPlayed => {
+                            let session = state.session.clone();
+                            drop(state);
+                            self.get_recently_played_node(&session).await
+                        }
+                        PlaylistType::SavedAlbums
+                        | PlaylistType::FollowedArtists
+                        | PlaylistType::Podcasts => {
+                            let session = state.session.clone();
+                            let names = Arc::clone(&state.library_names);
+                            drop(state);
+                            self.get_library_node(&session, &names, playlist_type, offset, limit)
+                                .await
+                        }
+                        _ => {
+                            self.get_playlist_node(&state, playlist_type, &node.id, &folder_path)
+                                .await
+                        }
+                    }
                 }
             }
             SessionStatus::Disconnect => {
@@ -281,47 +1006,363 @@ impl PluginService for Plugin {
         };
         Ok(Response::new(reply))
     }
-    async fn browse(
+    async fn cache_status(
         &self,
-        request: Request<BrowseRequest>,
-    ) -> Result<Response<BrowseReply>, Status> {
-        let conn_info = request.extensions().get::<UdsConnectInfo>().unwrap();
-        info!("Got a request {:?} with info {:?}", request, conn_info);
-
-        let reply = match request.into_inner().node {
-            None => self.get_root_node().await?,
-            Some(node) => self.get_node(&node).await?,
+        _: Request<CacheStatusRequest>,
+    ) -> Result<Response<CacheStatusReply>, Status> {
+        let dir = self.cache_dir()?;
+        let size = tokio::task::spawn_blocking(move || storage::usage(&dir))
+            .await
+            .map_err(|e| Status::new(Code::Internal, e.to_string()))?
+            .map_err(|e| Status::new(Code::Internal, format!("Cannot read cache: {:}", e)))?;
+        let limit = self.config.cache_size_limit.unwrap_or_default();
+        if limit > 0 && size > limit {
+            warn!("Cache is using {} bytes, over its limit of {}", size, limit);
+        }
+        Ok(Response::new(CacheStatusReply { size, limit }))
+    }
+    async fn evict_cache(
+        &self,
+        request: Request<EvictCacheRequest>,
+    ) -> Result<Response<EvictCacheReply>, Status> {
+        let target = request.into_inner().target_size;
+        let dir = self.cache_dir()?;
+        let (evicted_files, freed_bytes) =
+            tokio::task::spawn_blocking(move || storage::evict(&dir, target))
+                .await
+                .map_err(|e| Status::new(Code::Internal, e.to_string()))?
+                .map_err(|e| Status::new(Code::Internal, format!("Cannot evict cache: {:}", e)))?;
+        Ok(Response::new(EvictCacheReply {
+            evicted_files,
+            freed_bytes,
+        }))
+    }
+    async fn verify_cache(
+        &self,
+        _: Request<VerifyCacheRequest>,
+    ) -> Result<Response<VerifyCacheReply>, Status> {
+        let dir = self.cache_dir()?;
+        let (cache, offline) = {
+            let state = self.state.lock().await;
+            let offline = state.loader.lock().await.offline().cloned();
+            (state.session.cache().cloned(), offline)
         };
+        let (removed_files, freed_bytes) = tokio::task::spawn_blocking(move || {
+            let (mut removed, mut freed) = storage::remove_invalid(&dir)?;
+            // Files are only decrypted, and their content checked, through their offline entry.
+            if let (Some(cache), Some(offline)) = (cache, offline) {
+                let (corrupt, corrupt_bytes) = audio::loader::verify_offline(&cache, &offline);
+                removed += corrupt;
+                freed += corrupt_bytes;
+            }
+            std::io::Result::Ok((removed, freed))
+        })
+        .await
+        .map_err(|e| Status::new(Code::Internal, e.to_string()))?
+        .map_err(|e| Status::new(Code::Internal, format!("Cannot verify cache: {:}", e)))?;
+        info!(
+            "Removed {} invalid files ({} bytes) from the cache",
+            removed_files, freed_bytes
+        );
+        Ok(Response::new(VerifyCacheReply {
+            removed_files,
+            freed_bytes,
+        }))
+    }
+    async fn test_login(
+        &self,
+        request: Request<TestLoginRequest>,
+    ) -> Result<Response<TestLoginReply>, Status> {
+        let request = request.into_inner();
+        let form = LoginForm::new(
+            request.username,
+            request.password,
+            Some(request.token).filter(|token| !token.is_empty()),
+        );
+        if let Err(error) = form.validate() {
+            return Ok(Response::new(TestLoginReply {
+                success: false,
+                error,
+            }));
+        }
 
+        // A throwaway session without cache, so the credentials are never persisted nor
+        // replace the ones of the current session.
+        let session = Session::new(PluginState::session_config(&self.config), None);
+        let reply = match session.connect(form.into(), false).await {
+            Ok(()) => {
+                info!("Test login succeeded");
+                TestLoginReply {
+                    success: true,
+                    error: String::new(),
+                }
+            }
+            Err(e) => {
+                info!("Test login failed: {}", e);
+                TestLoginReply {
+                    success: false,
+                    error: e.to_string(),
+                }
+            }
+        };
+        session.shutdown();
         Ok(Response::new(reply))
     }
-    async fn event(&self, request: Request<ViewEvent>) -> Result<Response<SideEffect>, Status> {
-        let conn_info = request.extensions().get::<UdsConnectInfo>().unwrap();
-        info!("Got a request {:?} with info {:?}", request, conn_info);
-
-        match request.into_inner().view_event_oneof {
-            Some(ViewEventOneof::Submit(event)) => {
-                let form = serde_urlencoded::from_bytes::<LoginForm>(&event.payload).unwrap();
-                info!("login with {:?}", form);
-
-                info!("Connecting...");
+    async fn cancel(
+        &self,
+        request: Request<CancelRequest>,
+    ) -> Result<Response<CancelReply>, Status> {
+        let stream_id = request.into_inner().stream_id;
+        let cancelled = self.streams.cancel(&stream_id);
+        if cancelled {
+            info!("Cancelling stream {}", stream_id);
+        }
+        Ok(Response::new(CancelReply { cancelled }))
+    }
+    async fn account(&self, _: Request<AccountRequest>) -> Result<Response<AccountReply>, Status> {
+        let state = self.state.lock().await;
+        if !matches!(state.status, SessionStatus::Connected(_)) {
+            return Err(Status::new(Code::FailedPrecondition, "not logged in"));
+        }
+        Ok(Response::new(AccountReply {
+            username: state.session.username(),
+            premium: metadata::has_premium(&state.session) == Some(true),
+            explicit_content_allowed: metadata::explicit_content_allowed(&state.session),
+        }))
+    }
+    async fn connection_status(
+        &self,
+        request: Request<ConnectionStatusRequest>,
+    ) -> Result<Response<ConnectionStatusReply>, Status> {
+        let check_playback = request.into_inner().check_playback;
+        let state = self.state.lock().await;
+        let has_cached_credentials = state
+            .session
+            .cache()
+            .is_some_and(|cache| cache.credentials().is_some());
+        let reply = match &state.status {
+            SessionStatus::Disconnect => ConnectionStatusReply {
+                state: ConnectionState::StateDisconnected.into(),
+                has_cached_credentials,
+                ..ConnectionStatusReply::default()
+            },
+            SessionStatus::Connected(_) => ConnectionStatusReply {
+                state: ConnectionState::StateConnected.into(),
+                username: state.session.username(),
+                has_cached_credentials,
+                ..ConnectionStatusReply::default()
+            },
+            SessionStatus::Failed(error) => ConnectionStatusReply {
+                state: ConnectionState::StateFailed.into(),
+                error: error.clone(),
+                has_cached_credentials,
+                ..ConnectionStatusReply::default()
+            },
+        };
+        if !check_playback || reply.state() != ConnectionState::StateConnected {
+            return Ok(Response::new(reply));
+        }
 
-                let lock = Arc::clone(&self.state);
-                let mut state = lock.lock().await;
-                state.status = match state.session.connect(form.into(), true).await {
-                    Ok(()) => {
+        // Don't hold the state during the round trip to Spotify.
+        let session = state.session.clone();
+        drop(state);
+        let track = SpotifyId::from_uri(PLAYBACK_CHECK_TRACK_URI)
+            .map_err(|e| Status::new(Code::Internal, e.to_string()))?;
+        let checked = tokio::time::timeout(
+            PLAYBACK_CHECK_TIMEOUT,
+            audio::loader::check_playback(&session, track),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(LoaderError::Unavailable(
+                "Spotify didn't answer in time".to_owned(),
+            ))
+        });
+        let reply = match checked {
+            Ok(()) => reply,
+            Err(e) => {
+                warn!("Connected, but tracks can't be played: {}", e);
+                ConnectionStatusReply {
+                    state: ConnectionState::StateDegraded.into(),
+                    error: e.to_string(),
+                    ..reply
+                }
+            }
+        };
+        Ok(Response::new(reply))
+    }
+    async fn logout(
+        &self,
+        request: Request<LogoutRequest>,
+    ) -> Result<Response<LogoutReply>, Status> {
+        let args = request.into_inner();
+        let mut state = self.state.lock().await;
+        // Streams hold the loader of the session being dropped, stop them before they
+        // fail on it.
+        let cancelled_streams = self.streams.cancel_all();
+        if let SessionStatus::Connected(_) = state.status {
+            info!("Logging out {}", state.session.username());
+        }
+        state.reset();
+        if let (true, Some(dir)) = (args.forget_credentials, &self.config.credentials_dir) {
+            match std::fs::remove_file(dir.join(CREDENTIALS_FILE)) {
+                Ok(()) => info!("Removed cached credentials"),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    error!("Unable to remove cached credentials: {}", e);
+                    return Err(Status::new(
+                        Code::Internal,
+                        "unable to remove cached credentials",
+                    ));
+                }
+            }
+        }
+        Ok(Response::new(LogoutReply {
+            cancelled_streams: cancelled_streams as u32,
+        }))
+    }
+    type SearchStream = Pin<Box<dyn Stream<Item = Result<SearchResult, Status>> + Send + Sync>>;
+    async fn search(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<Self::SearchStream>, Status> {
+        let args = request.into_inner();
+        let query = args.query.trim().to_owned();
+        if query.is_empty() {
+            return Err(Status::new(Code::InvalidArgument, "query cannot be empty"));
+        }
+        let kind = match args.r#type() {
+            SearchType::Tracks => SearchKind::Tracks,
+            SearchType::Albums => SearchKind::Albums,
+            SearchType::Artists => SearchKind::Artists,
+            SearchType::Playlists => SearchKind::Playlists,
+        };
+        let offset = cmp::max(args.offset, 0) as usize;
+        // Without paging, searches still return as many results as Spotify allows.
+        let limit = match (args.limit, self.config.default_page_size) {
+            (limit, _) if limit > 0 => cmp::min(limit as usize, MAX_SEARCH_RESULTS),
+            (_, 0) => MAX_SEARCH_RESULTS,
+            (_, page_size) => cmp::min(page_size, MAX_SEARCH_RESULTS),
+        };
+
+        let (tx, rx) = mpsc::channel(4);
+
+        // The state isn't held while searching, for the other requests not to wait on it.
+        let (session, market) = {
+            let mut state = self.state.lock().await;
+            state.revive().await;
+            if !matches!(state.status, SessionStatus::Connected(_)) {
+                return Err(Status::new(
+                    Code::Unauthenticated,
+                    "not connected to Spotify",
+                ));
+            }
+            (state.session.clone(), state.market())
+        };
+
+        tokio::spawn(async move {
+            let hits = match search::search(&session, &query, kind, offset, limit, &market).await {
+                Ok(hits) => hits,
+                Err(e) => {
+                    if let Err(e2) = tx
+                        .send(Result::<SearchResult, Status>::Err(Status::new(
+                            Code::Unavailable,
+                            format!("unable to search: {:}", e),
+                        )))
+                        .await
+                    {
+                        error!("Unable to send error to client: {}", e2);
+                    }
+                    return;
+                }
+            };
+
+            for hit in hits {
+                let result = match kind {
+                    SearchKind::Tracks => match get_item(&session, &hit.id, &market).await {
+                        Ok(track) => SearchResultOneof::Track(track),
+                        Err(e) => {
+                            debug!("Skipping search hit {}: {}", hit.id, e);
+                            continue;
+                        }
+                    },
+                    // Artists are browsed into their albums, the other kinds are tracklists.
+                    SearchKind::Artists => SearchResultOneof::Node(Node {
+                        r#type: NodeType::Node.into(),
+                        label: display_name(&hit.name),
+                        id: hit.id.to_string(),
+                        icon: vec![],
+                        base62_id: base62_id(&hit.id),
+                    }),
+                    SearchKind::Albums | SearchKind::Playlists => SearchResultOneof::Node(Node {
+                        r#type: NodeType::Leaf.into(),
+                        label: display_name(&hit.name),
+                        id: hit.id.to_string(),
+                        icon: vec![],
+                        base62_id: base62_id(&hit.id),
+                    }),
+                };
+                if tx
+                    .send(Ok(SearchResult {
+                        search_result_oneof: Some(result),
+                    }))
+                    .await
+                    .is_err()
+                {
+                    // output_stream was build from rx and both are dropped
+                    return;
+                }
+            }
+        });
+
+        let output_stream = ReceiverStream::new(rx);
+        Ok(Response::new(Box::pin(output_stream) as Self::SearchStream))
+    }
+    async fn browse(
+        &self,
+        request: Request<BrowseRequest>,
+    ) -> Result<Response<BrowseReply>, Status> {
+        let conn_info = request.extensions().get::<UdsConnectInfo>().unwrap();
+        info!("Got a request {:?} with info {:?}", request, conn_info);
+
+        let request = request.into_inner();
+        let offset = cmp::max(request.offset, 0) as usize;
+        let limit = Some(request.limit)
+            .filter(|limit| *limit > 0)
+            .map(|limit| limit as usize);
+        let reply = match request.node {
+            None => self.get_root_node().await?,
+            Some(node) => self.get_node(&node, offset, limit).await?,
+        };
+
+        Ok(Response::new(reply))
+    }
+    async fn event(&self, request: Request<ViewEvent>) -> Result<Response<SideEffect>, Status> {
+        let conn_info = request.extensions().get::<UdsConnectInfo>().unwrap();
+        info!("Got a request {:?} with info {:?}", request, conn_info);
+
+        match request.into_inner().view_event_oneof {
+            Some(ViewEventOneof::Submit(event)) => {
+                let form = match serde_urlencoded::from_bytes::<LoginForm>(&event.payload)
+                    .map_err(|e| e.to_string())
+                    .and_then(|form| form.validate().map(|_| form))
+                {
+                    Ok(form) => form,
+                    Err(e) => {
+                        warn!("Rejecting login form: {}", e);
+                        return Ok(Response::new(SideEffect { error: e }));
+                    }
+                };
+                info!("login with {:?}", form);
+
+                info!("Connecting...");
+
+                let lock = Arc::clone(&self.state);
+                let mut state = lock.lock().await;
+                state.status = match state.session.connect(form.into(), true).await {
+                    Ok(()) => {
                         info!("Connected!");
-                        SessionStatus::Connected(Box::new(
-                            librespot_metadata::Rootlist::get(
-                                &state.session,
-                                &SpotifyId {
-                                    id: 0,
-                                    item_type: SpotifyItemType::Unknown,
-                                },
-                            )
-                            .await
-                            .map_err(|e| Status::new(Code::InvalidArgument, e.to_string()))?,
-                        ))
+                        SessionStatus::Connected(fetch_rootlist(&state.session).await)
                     }
                     Err(e) => {
                         info!("Error connecting: {}", e);
@@ -342,20 +1383,374 @@ impl PluginService for Plugin {
     }
 }
 
+/// Error of the requests about a track which don't name any.
+fn missing_track() -> Status {
+    Status::new(Code::InvalidArgument, "missing track")
+}
+
+/// Hand the id of a stream to the client, for it to be able to cancel it.
+fn set_stream_id<T>(response: &mut Response<T>, stream_id: &str) {
+    match stream_id.parse() {
+        Ok(value) => {
+            response.metadata_mut().insert(STREAM_ID_HEADER, value);
+        }
+        Err(e) => warn!("Cannot send stream id {:?}: {}", stream_id, e),
+    }
+}
+
+/// Key the next item of a tracklist by its ref and playlist entry id, e.g.
+/// `spotify:track:xxx#0a1b`. Items without an entry id are keyed by occurrence instead, e.g.
+/// `spotify:track:xxx#1` for the second occurrence of a track, given the occurrences of the items
+/// before it. Unlike the position, the key of an item doesn't change when other items get added
+/// or removed.
+fn ordering_key(occurrences: &mut HashMap<SpotifyId, usize>, item: &TracklistItem) -> String {
+    if !item.uid.is_empty() {
+        return format!("{}#{}", item.id, item.uid);
+    }
+    let occurrence = occurrences.entry(item.id).or_default();
+    let key = format!("{}#{}", item.id, occurrence);
+    *occurrence += 1;
+    key
+}
+
+/// Pick the image to use as artwork, preferring the default size.
+fn preferred_image<'a>(mut images: impl Iterator<Item = &'a Image> + Clone) -> Option<&'a Image> {
+    images
+        .clone()
+        .find(|image| image.size == ImageSize::DEFAULT)
+        .or_else(|| images.next())
+}
+
+fn cover_image(album: &Album) -> Option<&Image> {
+    preferred_image(album.cover_group.iter().chain(album.covers.iter()))
+}
+
+/// Fetch an artwork given either its image id or the ref of the track it belongs to.
+async fn get_artwork(
+    session: &Session,
+    cache: &std::sync::Mutex<ArtworkCache>,
+    id: &str,
+) -> Result<Vec<u8>, Status> {
+    let image_id = match metadata::parse_file_id(id) {
+        Some(image_id) => image_id,
+        None => {
+            let track = metadata::parse_uri(id)
+                .ok()
+                .filter(|track| track.item_type == SpotifyItemType::Track)
+                .ok_or_else(|| {
+                    Status::new(
+                        Code::InvalidArgument,
+                        format!("artwork id {:} is invalid", id),
+                    )
+                })?;
+            let track = librespot_metadata::Track::get(session, &track)
+                .await
+                .map_err(|e| {
+                    Status::new(Code::Unavailable, format!("unable to get track: {:}", e))
+                })?;
+            cover_image(&track.album)
+                .map(|image| image.id)
+                .ok_or_else(|| Status::new(Code::NotFound, format!("{:} has no artwork", id)))?
+        }
+    };
+
+    artwork::fetch_artwork(session, cache, image_id)
+        .await
+        .map_err(|e| Status::new(Code::Unavailable, format!("unable to get artwork: {:}", e)))
+}
+
+/// Embed the artwork of a track, if it has any. Failing to fetch it is not fatal, the track
+/// is then left without artwork.
+async fn fill_artwork(
+    session: &Session,
+    cache: &std::sync::Mutex<ArtworkCache>,
+    track: &mut Track,
+) {
+    if track.artwork_id.is_empty() {
+        return;
+    }
+    match get_artwork(session, cache, &track.artwork_id).await {
+        Ok(data) => track.artwork = data,
+        Err(e) => debug!("No artwork for {}: {}", track.r#ref, e.message()),
+    }
+}
+
+/// Preload the tracks following `track` in `tracklist`, so they open instantly when played in
+/// order. Only the first occurrence of `track` is considered, and the tracklist is listed page
+/// by page until the tracks following it are.
+async fn preload_following(
+    session: &Session,
+    loader: &Arc<tokio::sync::Mutex<TrackLoader>>,
+    tracklist: &TracklistRef,
+    track: SpotifyId,
+    market: &str,
+    options: OpenOptions,
+) {
+    let mut pages = metadata::TracklistPages::new(session, *tracklist, market, 0, None);
+    // Set once `track` is found, then filled with the tracks following it.
+    let mut following: Option<Vec<SpotifyId>> = None;
+    loop {
+        let items = match pages.next().await {
+            Ok(Some((_, items))) => items,
+            // Past the end of the tracklist, there is simply nothing left to preload.
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Not preloading after {}: {}", track, e);
+                return;
+            }
+        };
+        let mut items = items.into_iter().map(|item| item.id);
+        if following.is_none() && items.by_ref().all(|item| item != track) {
+            continue;
+        }
+        let following = following.get_or_insert_with(Vec::new);
+        following.extend(items.take(PRELOAD_AHEAD - following.len()));
+        if following.len() == PRELOAD_AHEAD {
+            break;
+        }
+    }
+    let Some(following) = following else {
+        debug!("{} is not in {}, nothing to preload", track, tracklist);
+        return;
+    };
+    let options = OpenOptions {
+        format: None,
+        ..options
+    };
+    for next in following {
+        match TrackLoader::preload_shared(loader, next, &options).await {
+            Ok(true) => info!("Preloaded {} after {}", next, track),
+            Ok(false) => {}
+            Err(e) => warn!("Unable to preload {}: {}", next, e),
+        }
+    }
+}
+
+/// Resolve the metadata of a tracklist item, which is either a track or a podcast episode.
+async fn get_item(
+    session: &Session,
+    id: &SpotifyId,
+    market: &str,
+) -> Result<Track, librespot_core::Error> {
+    let (track, restrictions) = match id.item_type {
+        SpotifyItemType::Episode => {
+            let episode = librespot_metadata::Episode::get(session, id).await?;
+            let restrictions = episode.restrictions.clone();
+            (Track::from(episode), restrictions)
+        }
+        _ => {
+            let track = metadata::get_track_in_market(session, id, market).await?;
+            let restrictions = track.restrictions.clone();
+            (Track::from(track), restrictions)
+        }
+    };
+    Ok(Track {
+        available: market.is_empty() || metadata::available_in(&restrictions, market),
+        ..track
+    })
+}
+
+/// Market requested by the client, as a two-letter country code. `None` when the account's
+/// own market should be used.
+fn parse_market(market: &str) -> Result<Option<String>, Status> {
+    let market = market.trim();
+    if market.is_empty() {
+        Ok(None)
+    } else if market.len() == 2 && market.chars().all(|c| c.is_ascii_alphabetic()) {
+        Ok(Some(market.to_ascii_uppercase()))
+    } else {
+        Err(Status::new(
+            Code::InvalidArgument,
+            format!("market {:?} is not a two-letter country code", market),
+        ))
+    }
+}
+
+/// Grow the chunk size while chunks are served quickly, and shrink it as soon as
+/// reading stalls on the network, staying within the `read` chunk size bounds.
+fn adapt_chunk_size(chunk_size: usize, max_chunk_size: usize, elapsed: Duration) -> usize {
+    if elapsed < ADAPTIVE_CHUNK_FAST {
+        cmp::min(chunk_size * 2, max_chunk_size)
+    } else if elapsed > ADAPTIVE_CHUNK_SLOW {
+        cmp::max(chunk_size / 2, MIN_READ_CHUNK_SIZE)
+    } else {
+        chunk_size
+    }
+}
+
+/// Id of an item as used by Spotify's Web API, empty for ids that have none.
+fn base62_id(id: &SpotifyId) -> String {
+    id.to_base62().unwrap_or_default()
+}
+
+/// Clean up a name coming from Spotify before handing it to Mixxx: control and
+/// zero-width characters are dropped and surrounding whitespace is trimmed.
+fn display_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| !c.is_control() && !matches!(c, '\u{200b}' | '\u{feff}'))
+        .collect::<String>()
+        .trim()
+        .to_owned()
+}
+
+fn artist_role(role: ArtistRole) -> pb::ArtistRole {
+    match role {
+        ArtistRole::ARTIST_ROLE_UNKNOWN => pb::ArtistRole::RoleUnknown,
+        ArtistRole::ARTIST_ROLE_MAIN_ARTIST => pb::ArtistRole::RoleMain,
+        ArtistRole::ARTIST_ROLE_FEATURED_ARTIST => pb::ArtistRole::RoleFeatured,
+        ArtistRole::ARTIST_ROLE_REMIXER => pb::ArtistRole::RoleRemixer,
+        ArtistRole::ARTIST_ROLE_ACTOR => pb::ArtistRole::RoleActor,
+        ArtistRole::ARTIST_ROLE_COMPOSER => pb::ArtistRole::RoleComposer,
+        ArtistRole::ARTIST_ROLE_CONDUCTOR => pb::ArtistRole::RoleConductor,
+        ArtistRole::ARTIST_ROLE_ORCHESTRA => pb::ArtistRole::RoleOrchestra,
+    }
+}
+
+impl From<LoaderError> for Status {
+    fn from(e: LoaderError) -> Self {
+        let code = match e {
+            LoaderError::NotOpen | LoaderError::NotFound(_) => Code::NotFound,
+            LoaderError::NotReferenced => Code::FailedPrecondition,
+            LoaderError::UnsupportedFormat(_) | LoaderError::RegionLocked(_) => {
+                Code::FailedPrecondition
+            }
+            LoaderError::NoSupportedFormat(_) => Code::Unimplemented,
+            LoaderError::Unavailable(_) | LoaderError::NotOffline | LoaderError::Fetch(_) => {
+                Code::Unavailable
+            }
+            LoaderError::Io(_) => Code::Internal,
+        };
+        Status::new(code, e.to_string())
+    }
+}
+
+/// Whether all the letters of `name` are in the Latin script.
+fn is_latin(name: &str) -> bool {
+    name.chars()
+        .filter(|c| c.is_alphabetic())
+        .all(|c| c < '\u{0250}' || ('\u{1e00}'..='\u{1eff}').contains(&c))
+}
+
 impl From<librespot_metadata::Track> for Track {
     fn from(value: librespot_metadata::Track) -> Self {
+        let artwork_id = cover_image(&value.album)
+            .map(|image| image.id.to_string())
+            .unwrap_or_default();
+        let preview_url = value
+            .previews
+            .get(&AudioFileFormat::MP3_96)
+            .or_else(|| value.previews.values().next())
+            .map(|file_id| format!("{}{}", PREVIEW_URL_BASE, file_id))
+            .unwrap_or_default();
+        let artist = value
+            .artists
+            .iter()
+            .map(|a| display_name(&a.name))
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<_>>()
+            .join(", ");
+        // Spotify names tracks with an original title in a non-Latin script after their
+        // romanization.
+        let original_title = display_name(&value.original_title);
+        let romanized_title = if !original_title.is_empty() && !is_latin(&original_title) {
+            Some(display_name(&value.name))
+                .filter(|name| *name != original_title && is_latin(name))
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+        let album_artist = value
+            .album
+            .artists
+            .iter()
+            .map(|a| display_name(&a.name))
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<_>>()
+            .join(", ");
         Self {
             id: value.id.id as i64,
             r#ref: value.id.to_string(),
-            title: value.original_title,
-            artist: value
-                .artists
-                .iter()
-                .map(|a| a.name.to_owned())
-                .collect::<Vec<_>>()
-                .join(", "),
-            album: value.album.name,
+            base62_id: base62_id(&value.id),
+            title: if original_title.is_empty() {
+                display_name(&value.name)
+            } else {
+                original_title
+            },
+            romanized_title,
+            // Albums fetched along with a track may come without their artists, in which
+            // case the track artists are the best guess.
+            album_artist: if album_artist.is_empty() {
+                artist.clone()
+            } else {
+                album_artist
+            },
+            artist,
+            // Only some tracks come with the credits of their artists.
+            artists: if value.artists_with_role.is_empty() {
+                value
+                    .artists
+                    .iter()
+                    .map(|a| Artist {
+                        name: display_name(&a.name),
+                        r#ref: a.id.to_string(),
+                        role: pb::ArtistRole::RoleUnknown.into(),
+                    })
+                    .collect()
+            } else {
+                value
+                    .artists_with_role
+                    .iter()
+                    .map(|a| Artist {
+                        name: display_name(&a.name),
+                        r#ref: a.id.to_string(),
+                        role: artist_role(a.role).into(),
+                    })
+                    .collect()
+            },
+            album: display_name(&value.album.name),
             artwork: vec![],
+            artwork_id,
+            preview_url,
+            duration_ms: value.duration,
+            popularity: value.popularity,
+            track_number: value.number,
+            disc_number: value.disc_number,
+            explicit: value.is_explicit,
+            ..Self::default()
+        }
+    }
+}
+
+impl From<librespot_metadata::Show> for pb::Show {
+    fn from(value: librespot_metadata::Show) -> Self {
+        Self {
+            r#ref: value.id.to_string(),
+            base62_id: base62_id(&value.id),
+            name: display_name(&value.name),
+            publisher: display_name(&value.publisher),
+            description: value.description.trim().to_owned(),
+            artwork_id: preferred_image(value.covers.iter())
+                .map(|image| image.id.to_string())
+                .unwrap_or_default(),
+            episode_count: value.episodes.len() as i32,
+        }
+    }
+}
+
+impl From<librespot_metadata::Episode> for Track {
+    fn from(value: librespot_metadata::Episode) -> Self {
+        Self {
+            id: value.id.id as i64,
+            r#ref: value.id.to_string(),
+            base62_id: base62_id(&value.id),
+            title: display_name(&value.name),
+            artist: display_name(&value.show_name),
+            artwork_id: preferred_image(value.covers.iter())
+                .map(|image| image.id.to_string())
+                .unwrap_or_default(),
+            duration_ms: value.duration,
+            explicit: value.is_explicit,
+            ..Self::default()
         }
     }
 }
@@ -364,58 +1759,449 @@ impl From<librespot_metadata::Track> for Track {
 impl TrackService for Plugin {
     async fn get(&self, req: Request<TrackRequest>) -> Result<Response<TrackResponse>, Status> {
         let req = req.into_inner();
-        let track_ref = if req.r#ref.starts_with('/') {
-            req.r#ref[1..].to_owned()
+        let track_ref = req.r#ref;
+        let market = parse_market(&req.market)?;
+        let track = metadata::parse_uri(&track_ref).map_err(|_| {
+            Status::new(
+                Code::InvalidArgument,
+                format!("ref {:} is invalid", track_ref),
+            )
+        })?;
+        if !matches!(
+            track.item_type,
+            SpotifyItemType::Track | SpotifyItemType::Episode
+        ) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("ref {:} is not a track", track_ref),
+            ));
+        }
+
+        let lock = Arc::clone(&self.state);
+        let mut state = lock.lock().await;
+        state.revive().await;
+
+        let market = market.unwrap_or_else(|| state.market());
+        let mut reply = get_item(&state.session, &track, &market)
+            .await
+            .map_err(|e| Status::new(Code::Unavailable, format!("unable to get track: {:}", e)))?;
+        // Not every track has been analysed, fades are left at zero for those and episodes.
+        if track.item_type == SpotifyItemType::Track {
+            match metadata::get_fades(&state.session, &track).await {
+                Ok((fade_in_ms, fade_out_ms)) => {
+                    reply.fade_in_ms = fade_in_ms;
+                    reply.fade_out_ms = fade_out_ms;
+                }
+                Err(e) => debug!("No audio analysis for {}: {}", track, e),
+            }
+        }
+        fill_artwork(&state.session, &self.artwork, &mut reply).await;
+        Ok(Response::new(TrackResponse { track: Some(reply) }))
+    }
+    type GetBatchStream = Pin<Box<dyn Stream<Item = Result<TrackBatchItem, Status>> + Send + Sync>>;
+    async fn get_batch(
+        &self,
+        req: Request<TrackBatchRequest>,
+    ) -> Result<Response<Self::GetBatchStream>, Status> {
+        let req = req.into_inner();
+        let market = parse_market(&req.market)?;
+
+        let (tx, rx) = mpsc::channel(4);
+
+        let lock = Arc::clone(&self.state);
+        tokio::spawn(async move {
+            let mut state = lock.lock().await;
+            state.revive().await;
+
+            let market = market.unwrap_or_else(|| state.market());
+            let (session, market) = (&state.session, &market);
+            let mut items = stream::iter(req.refs)
+                .map(|track_ref| async move {
+                    let track = metadata::parse_uri(&track_ref)
+                        .ok()
+                        .filter(|id| {
+                            matches!(
+                                id.item_type,
+                                SpotifyItemType::Track | SpotifyItemType::Episode
+                            )
+                        })
+                        .ok_or_else(|| format!("ref {:} is not a track", track_ref));
+                    let track = match track {
+                        Ok(track) => get_item(session, &track, market)
+                            .await
+                            .map_err(|e| format!("unable to get track: {:}", e)),
+                        Err(e) => Err(e),
+                    };
+                    match track {
+                        Ok(track) => TrackBatchItem {
+                            r#ref: track_ref,
+                            track: Some(track),
+                            error: "".into(),
+                        },
+                        Err(error) => TrackBatchItem {
+                            r#ref: track_ref,
+                            track: None,
+                            error,
+                        },
+                    }
+                })
+                .buffered(METADATA_CONCURRENCY);
+
+            while let Some(item) = items.next().await {
+                if tx.send(Ok(item)).await.is_err() {
+                    // The client went away.
+                    return;
+                }
+            }
+        });
+
+        let output_stream = ReceiverStream::new(rx);
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::GetBatchStream
+        ))
+    }
+    async fn play_first_match(
+        &self,
+        req: Request<PlayFirstMatchRequest>,
+    ) -> Result<Response<TrackResponse>, Status> {
+        let req = req.into_inner();
+        let market = parse_market(&req.market)?;
+        let query = req.query.trim();
+        if query.is_empty() {
+            return Err(Status::new(Code::InvalidArgument, "query cannot be empty"));
+        }
+
+        let lock = Arc::clone(&self.state);
+        let state = lock.lock().await;
+
+        let market = market.unwrap_or_else(|| state.market());
+        let track = search::search_tracks(&state.session, query, 1, &market)
+            .await
+            .map_err(|e| Status::new(Code::Unavailable, format!("unable to search: {:}", e)))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Status::new(Code::NotFound, format!("no track matches {:?}", query)))?;
+        info!("{:?} resolved to {}", query, track);
+        let mut reply = get_item(&state.session, &track, &market)
+            .await
+            .map_err(|e| Status::new(Code::Unavailable, format!("unable to get track: {:}", e)))?;
+        fill_artwork(&state.session, &self.artwork, &mut reply).await;
+        Ok(Response::new(TrackResponse { track: Some(reply) }))
+    }
+    async fn get_show(&self, req: Request<ShowRequest>) -> Result<Response<ShowResponse>, Status> {
+        let show_ref = req.into_inner().r#ref;
+        let id = metadata::parse_uri(&show_ref).map_err(|_| {
+            Status::new(
+                Code::InvalidArgument,
+                format!("ref {:} is invalid", show_ref),
+            )
+        })?;
+
+        let lock = Arc::clone(&self.state);
+        let state = lock.lock().await;
+
+        let show =
+            match id.item_type {
+                SpotifyItemType::Show => id,
+                SpotifyItemType::Episode => metadata::episode_show(&state.session, &id)
+                    .await
+                    .map_err(|e| {
+                        Status::new(
+                            Code::Unavailable,
+                            format!("unable to get the show of {:}: {:}", show_ref, e),
+                        )
+                    })?,
+                _ => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        format!("ref {:} is neither a show nor an episode", show_ref),
+                    ))
+                }
+            };
+
+        librespot_metadata::Show::get(&state.session, &show)
+            .await
+            .map(|s| {
+                Response::new(ShowResponse {
+                    show: Some(s.into()),
+                })
+            })
+            .map_err(|e| Status::new(Code::Unavailable, format!("unable to get show: {:}", e)))
+    }
+    async fn get_artwork(
+        &self,
+        req: Request<ArtworkRequest>,
+    ) -> Result<Response<ArtworkResponse>, Status> {
+        let req = req.into_inner();
+
+        let lock = Arc::clone(&self.state);
+        let state = lock.lock().await;
+
+        get_artwork(&state.session, &self.artwork, &req.id)
+            .await
+            .map(|data| Response::new(ArtworkResponse { data }))
+    }
+    async fn get_artwork_batch(
+        &self,
+        req: Request<ArtworkBatchRequest>,
+    ) -> Result<Response<ArtworkBatchResponse>, Status> {
+        let req = req.into_inner();
+        if req.ids.len() > MAX_ARTWORK_BATCH {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "cannot fetch more than {} artworks at once",
+                    MAX_ARTWORK_BATCH
+                ),
+            ));
+        }
+
+        let lock = Arc::clone(&self.state);
+        let state = lock.lock().await;
+
+        let session = &state.session;
+        let cache = &self.artwork;
+        let artworks = stream::iter(req.ids)
+            .map(|id| async move {
+                match get_artwork(session, cache, &id).await {
+                    Ok(data) => Artwork {
+                        id,
+                        data,
+                        error: "".into(),
+                    },
+                    Err(e) => Artwork {
+                        id,
+                        data: vec![],
+                        error: e.message().to_owned(),
+                    },
+                }
+            })
+            .buffered(ARTWORK_CONCURRENCY)
+            .collect()
+            .await;
+
+        Ok(Response::new(ArtworkBatchResponse { artworks }))
+    }
+    async fn open(&self, req: Request<OpenRequest>) -> Result<Response<OpenResponse>, Status> {
+        let req = req.into_inner();
+        let track_ref = req.track.ok_or_else(missing_track)?.r#ref;
+        let track = metadata::parse_uri(&track_ref).map_err(|_| {
+            Status::new(
+                Code::InvalidArgument,
+                format!("ref {:} is not a track", track_ref),
+            )
+        })?;
+        if !matches!(
+            track.item_type,
+            SpotifyItemType::Track | SpotifyItemType::Episode
+        ) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("ref {:} is not a track", track_ref),
+            ));
+        }
+
+        // Neither the state nor the loader stay locked while the track is fetched, so other
+        // tracks can be read meanwhile.
+        let (loader_lock, session, market) = {
+            let mut state = self.state.lock().await;
+            state.revive().await;
+            (
+                Arc::clone(&state.loader),
+                state.session.clone(),
+                state.market(),
+            )
+        };
+
+        let format = match req.format.as_str() {
+            "" => None,
+            name => Some(audio::loader::parse_format(name).ok_or_else(|| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("unsupported format {:}", name),
+                )
+            })?),
+        };
+        let bitrate = match req.bitrate {
+            0 => None,
+            kbps => Some(kbps.to_string().parse::<Bitrate>().map_err(|_| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("unsupported bitrate {:}", kbps),
+                )
+            })?),
+        };
+        let accepted: Vec<AudioFileFormat> = req
+            .supported_formats
+            .iter()
+            .filter_map(|name| audio::loader::parse_format(name))
+            .collect();
+        if accepted.is_empty() && !req.supported_formats.is_empty() {
+            return Err(Status::new(
+                Code::FailedPrecondition,
+                format!(
+                    "none of the client formats {:?} can be served",
+                    req.supported_formats
+                ),
+            ));
+        }
+        if let (Some(format), Some(opened)) = (format, loader_lock.lock().await.get_opened(&track))
+        {
+            // The requested format may not be offered by the track, so asking for the one
+            // actually served doesn't conflict either.
+            if opened.requested_format() != format && opened.format() != format {
+                match self.config.format_conflict {
+                    FormatConflict::Share => info!(
+                        "{} is already open as {:?}, ignoring the requested {:?}",
+                        track,
+                        opened.format(),
+                        format
+                    ),
+                    FormatConflict::Reject => {
+                        return Err(Status::new(
+                            Code::FailedPrecondition,
+                            format!(
+                                "{} is already open as {:?}, close it first",
+                                track_ref,
+                                opened.format()
+                            ),
+                        ))
+                    }
+                }
+            }
+        }
+
+        self.state.lock().await.player.preload(track);
+        // The duration sizing the WAV output is resolved before the loader gets locked, unless
+        // the track is already served decoded.
+        let duration_ms = if self.config.decode_wav
+            && !loader_lock
+                .lock()
+                .await
+                .get_opened(&track)
+                .is_some_and(|opened| opened.is_decoded())
+        {
+            match get_item(&session, &track, &market).await {
+                Ok(metadata) => Some(metadata.duration_ms as u32),
+                Err(e) => {
+                    warn!("No duration for {}, estimating it: {}", track, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let options = OpenOptions {
+            format,
+            accepted,
+            bitrate,
+        };
+        // Free accounts are denied the audio files, which isn't worth trying nor retrying.
+        let opened = if metadata::has_premium(&session) == Some(false) {
+            info!("Cannot open {} without premium", track);
+            Err(Status::new(Code::PermissionDenied, "premium required"))
+        } else {
+            let opening = TrackLoader::open_shared(&loader_lock, track, &options);
+            match self.config.open_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, opening).await.map_err(|_| {
+                    warn!("Opening {} timed out after {:?}", track, timeout);
+                    Status::new(
+                        Code::DeadlineExceeded,
+                        format!("opening {:} timed out", track_ref),
+                    )
+                }),
+                None => Ok(opening.await),
+            }
+            .and_then(|opened| opened.map_err(Status::from))
+        };
+        let mut loader = match (opened, self.config.placeholder_duration) {
+            (Ok(loader), _) => loader,
+            (Err(status), Some(duration)) => {
+                warn!(
+                    "Serving silence in place of {}: {}",
+                    track,
+                    status.message()
+                );
+                let mut loader = Arc::clone(&loader_lock).lock_owned().await;
+                loader.open_placeholder(track, duration.as_millis() as u32);
+                loader
+            }
+            (Err(status), None) => return Err(status),
+        };
+        if let Some(tracklist) = req
+            .tracklist
+            .filter(|tracklist| !tracklist.r#ref.is_empty())
+        {
+            match TracklistRef::parse(&tracklist.r#ref) {
+                Ok(tracklist) => {
+                    let (session, loader_lock) = (session.clone(), Arc::clone(&loader_lock));
+                    let (market, options) = (market.clone(), options.clone());
+                    tokio::spawn(async move {
+                        preload_following(
+                            &session,
+                            &loader_lock,
+                            &tracklist,
+                            track,
+                            &market,
+                            options,
+                        )
+                        .await
+                    });
+                }
+                Err(e) => warn!("Not preloading after {}: {}", track, e),
+            }
+        }
+        let opened = loader.get_opened(&track).ok_or(LoaderError::NotOpen)?;
+        if self.config.decode_wav && !opened.is_decoded() {
+            let duration_ms = duration_ms.unwrap_or_else(|| opened.estimated_duration_ms());
+            loader = TrackLoader::decode_shared(&loader_lock, loader, track, duration_ms)
+                .await
+                .map_err(|e| {
+                    Status::new(Code::Internal, format!("Couldn't decode track: {:}", e))
+                })?;
+        }
+        let opened = loader.get_opened_mut(&track).ok_or(LoaderError::NotOpen)?;
+        if !self.config.decode_wav
+            && self.config.ogg_remux
+            && AudioFiles::is_ogg_vorbis(opened.format())
+        {
+            opened.remux_ogg();
+        }
+        let decoded = opened.is_decoded();
+        let placeholder = opened.is_placeholder();
+        let load_time_ms = if cfg!(feature = "debug-rpc") {
+            opened.load_time().as_millis() as u32
         } else {
-            req.r#ref
+            0
         };
-        let track = SpotifyId::from_uri(&track_ref).map_err(|_| {
-            Status::new(
-                Code::InvalidArgument,
-                format!("ref {:} is invalid", track_ref),
-            )
-        })?;
-        if track.item_type != SpotifyItemType::Track {
-            return Err(Status::new(
-                Code::InvalidArgument,
-                format!("ref {:} is not a track", track_ref),
-            ));
+        let filesize = opened.len() as i64;
+        let normalisation = opened.normalisation().map(|n| pb::Normalisation {
+            track_gain_db: n.track_gain_db,
+            track_peak: n.track_peak,
+            album_gain_db: n.album_gain_db,
+            album_peak: n.album_peak,
+        });
+        let format = opened.format();
+        let from_cache = opened.is_cached();
+        let requested_format = opened.requested_format();
+        let downgraded = opened.is_downgraded();
+        if downgraded {
+            info!(
+                "Serving {} as {:?} instead of {:?}",
+                track, format, requested_format
+            );
         }
-
-        let lock = Arc::clone(&self.state);
-        let state = lock.lock().await;
-
-        librespot_metadata::Track::get(&state.session, &track)
-            .await
-            .map(|t| {
-                Response::new(TrackResponse {
-                    track: Some(t.into()),
-                })
-            })
-            .map_err(|e| Status::new(Code::Unavailable, format!("unable to get track: {:}", e)))
-    }
-    async fn open(&self, req: Request<OpenRequest>) -> Result<Response<OpenResponse>, Status> {
-        let req = req.into_inner();
-        let track_ref = req.track.unwrap().r#ref;
-        let track = SpotifyId::from_uri(&track_ref).map_err(|_| {
-            Status::new(
-                Code::InvalidArgument,
-                format!("ref {:} is not a track", track_ref),
-            )
-        })?;
-
-        let lock = Arc::clone(&self.state);
-        let state = lock.lock().await;
-
-        let loader_lock = Arc::clone(&state.loader);
-        let mut loader = loader_lock.lock().await;
-
-        state.player.preload(track);
-        let (filesize, format) = loader
-            .open(track)
-            .await
-            .map_err(|e| Status::new(Code::Unavailable, e))?;
+        let header = if req.peek_size > 0 {
+            opened
+                .peek(cmp::min(req.peek_size as usize, MAX_PEEK_SIZE))
+                .map_err(|e| {
+                    Status::new(Code::Internal, format!("Couldn't peek in file: {:}", e))
+                })?
+        } else {
+            vec![]
+        };
         let mime = match format {
+            _ if decoded => "audio/wav",
             AudioFileFormat::OGG_VORBIS_320
             | AudioFileFormat::OGG_VORBIS_160
             | AudioFileFormat::OGG_VORBIS_96 => "application/ogg",
@@ -423,27 +2209,106 @@ impl TrackService for Plugin {
             | AudioFileFormat::MP3_256
             | AudioFileFormat::MP3_160
             | AudioFileFormat::MP3_96 => "audio/mpeg",
+            AudioFileFormat::FLAC_FLAC => "audio/flac",
+            AudioFileFormat::AAC_24 | AudioFileFormat::AAC_48 => "audio/aac",
             _ => "application/octet-stream",
         }
         .to_owned();
-        Ok(Response::new(OpenResponse { filesize, mime }))
+        Ok(Response::new(OpenResponse {
+            filesize,
+            mime,
+            header,
+            from_cache,
+            format: format!("{:?}", format),
+            requested_format: format!("{:?}", requested_format),
+            downgraded,
+            placeholder,
+            load_time_ms,
+            normalisation,
+        }))
+    }
+    async fn preload(
+        &self,
+        req: Request<PreloadRequest>,
+    ) -> Result<Response<PreloadResponse>, Status> {
+        let req = req.into_inner();
+        let track_ref = req.track.ok_or_else(missing_track)?.r#ref;
+        let track = metadata::parse_uri(&track_ref)
+            .ok()
+            .filter(|id| {
+                matches!(
+                    id.item_type,
+                    SpotifyItemType::Track | SpotifyItemType::Episode
+                )
+            })
+            .ok_or_else(|| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("ref {:} is not a track", track_ref),
+                )
+            })?;
+        let format = match req.format.as_str() {
+            "" => None,
+            name => Some(audio::loader::parse_format(name).ok_or_else(|| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("unsupported format {:}", name),
+                )
+            })?),
+        };
+
+        let loader_lock = {
+            let mut state = self.state.lock().await;
+            state.revive().await;
+            state.player.preload(track);
+            Arc::clone(&state.loader)
+        };
+        let options = OpenOptions {
+            format,
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            match TrackLoader::preload_shared(&loader_lock, track, &options).await {
+                Ok(true) => info!("Preloaded {}", track),
+                Ok(false) => debug!("{} is already loaded", track),
+                Err(e) => warn!("Unable to preload {}: {}", track, e),
+            }
+        });
+        Ok(Response::new(PreloadResponse {}))
     }
     type ReadStream = Pin<Box<dyn Stream<Item = Result<ReadChunk, Status>> + Send + Sync>>;
     async fn read(&self, req: Request<ReadRequest>) -> Result<Response<Self::ReadStream>, Status> {
         let req = req.into_inner();
 
-        let mut track = SpotifyId::from_uri(&req.track.unwrap().r#ref)
+        let track = metadata::parse_uri(&req.track.ok_or_else(missing_track)?.r#ref)
             .map_err(|_| Status::new(Code::InvalidArgument, "track id is invalid"))?;
-        track.item_type = SpotifyItemType::Track;
         info!("Playing...");
 
-        let chunk_size: usize = if req.chunk_size == 0 {
-            10_240
-        } else {
-            cmp::max(128, cmp::min(req.chunk_size as usize, 10_240))
+        let max_chunk_size = cmp::max(MIN_READ_CHUNK_SIZE, self.config.fetch_buffer_size);
+        let chunk_size: usize = match req.chunk_size as usize {
+            0 => max_chunk_size,
+            requested => {
+                let chunk_size = requested.clamp(MIN_READ_CHUNK_SIZE, max_chunk_size);
+                if chunk_size != requested {
+                    debug!(
+                        "Chunk size {} out of bounds, using {} instead",
+                        requested, chunk_size
+                    );
+                }
+                chunk_size
+            }
         };
         let offset = req.offset;
         let limit = req.limit as usize;
+        let adaptive = req.adaptive_chunk_size;
+        let stream = self.register_stream(req.stream_id)?;
+        let stream_id = stream.id().to_owned();
+
+        let permit = Arc::clone(&self.read_streams)
+            .try_acquire_owned()
+            .map_err(|_| {
+                Status::new(Code::ResourceExhausted, "Too many concurrent read streams")
+            })?;
 
         let (tx, rx) = mpsc::channel(4);
 
@@ -452,31 +2317,95 @@ impl TrackService for Plugin {
         let loader_lock = Arc::clone(&state.loader);
 
         tokio::spawn(async move {
+            let _permit = permit;
             let mut loader = loader_lock.lock().await;
-
-            if let Some(loaded_track) = loader.get_opened_mut(&track) {
-                let mut read: usize = 0;
-                if let Err(e) = loaded_track.seek(SeekFrom::Start(offset)) {
-                    tx.send(Result::<_, Status>::Err(Status::new(
-                        Code::InvalidArgument,
-                        format!("Couldn't seek in file: {:}", e),
-                    )))
-                    .await
-                    .unwrap();
+            let mut chunk_size = chunk_size;
+            let mut read: usize = 0;
+            let mut reloaded = false;
+            let mut first_chunk = true;
+
+            loop {
+                let Some(loaded_track) = loader.get_opened_mut(&track) else {
+                    if let Err(e) = tx
+                        .send(Result::<_, Status>::Err(LoaderError::NotOpen.into()))
+                        .await
+                    {
+                        error!("Unable to send error to client: {}", e);
+                    }
+                    return;
+                };
+                let name = loaded_track.name().to_owned();
+                let position = offset + read as u64;
+                if let Err(e) = loaded_track.seek(SeekFrom::Start(position)) {
+                    if let Err(e2) = tx
+                        .send(Result::<_, Status>::Err(Status::new(
+                            Code::InvalidArgument,
+                            format!("Couldn't seek in file: {:}", e),
+                        )))
+                        .await
+                    {
+                        error!("Unable to send error to client: {}", e2);
+                    }
                     return;
                 }
-
-                info!("Reading up to {:} from {:}...", limit, offset);
-                loop {
-                    let mut buffer: Vec<u8> = vec![0; cmp::min(chunk_size, limit - read)];
+                loaded_track.start_read_ahead();
+
+                info!(
+                    "Reading up to {:} of <{}> from {:}...",
+                    limit.saturating_sub(read),
+                    name,
+                    position
+                );
+                let error = loop {
+                    if stream.is_cancelled() {
+                        info!("Reading <{}> cancelled after {:}", name, read);
+                        if let Err(e) = tx
+                            .send(Result::<_, Status>::Err(Status::new(
+                                Code::Cancelled,
+                                "Read was cancelled",
+                            )))
+                            .await
+                        {
+                            debug!("Unable to notify the cancellation of {}: {}", track, e);
+                        }
+                        break None;
+                    }
+                    let mut buffer: Vec<u8> =
+                        vec![0; cmp::min(chunk_size, limit.saturating_sub(read))];
                     info!("Reading chunk of {:}...", buffer.len());
+                    let started = Instant::now();
                     match loaded_track.read(&mut buffer) {
                         Ok(readsize) => {
                             read += readsize;
+                            if adaptive {
+                                chunk_size =
+                                    adapt_chunk_size(chunk_size, max_chunk_size, started.elapsed());
+                            }
+                            let total_length = if first_chunk {
+                                loaded_track.len() as u64
+                            } else {
+                                0
+                            };
+                            first_chunk = false;
+                            let end = if readsize == 0 {
+                                ReadEnd::Eof
+                            } else if read >= limit {
+                                ReadEnd::Limit
+                            } else {
+                                ReadEnd::None
+                            };
+                            let underrun = if end == ReadEnd::None {
+                                0
+                            } else {
+                                limit.saturating_sub(read) as u64
+                            };
                             match tx
                                 .send(Result::<_, Status>::Ok(ReadChunk {
                                     data: buffer[0..readsize].to_vec(),
                                     eof: readsize == 0,
+                                    total_length,
+                                    end: end.into(),
+                                    underrun,
                                 }))
                                 .await
                             {
@@ -485,66 +2414,101 @@ impl TrackService for Plugin {
                                 }
                                 Err(_item) => {
                                     // output_stream was build from rx and both are dropped
-                                    break;
+                                    break None;
                                 }
                             };
                             if readsize == 0 {
-                                info!("Reach EOF after {:}...", read);
-                                break;
+                                info!("Reach EOF of <{}> after {:}...", name, read);
+                                break None;
                             } else if read >= limit {
                                 info!("Read {:}...", read);
-                                break;
+                                break None;
                             }
                         }
-                        Err(e) => {
-                            if let Err(e2) = tx
+                        Err(e) => break Some(e),
+                    };
+                };
+
+                match error {
+                    None => break,
+                    Some(e) if !reloaded => {
+                        // The fetch may have been bound to a session that got reconnected in
+                        // the meantime, so restart it once from where we stopped.
+                        warn!("Cannot read <{}>, reloading it: {}", name, e);
+                        reloaded = true;
+                        if let Err(e2) = loader.reload(track).await {
+                            error!("Unable to reload <{}>: {}", name, e2);
+                            if let Err(e3) = tx
                                 .send(Result::<_, Status>::Err(Status::new(
                                     Code::Internal,
-                                    format!("Cannot read track: {:}", e),
+                                    format!(
+                                        "Cannot read track, {:} bytes short: {:}",
+                                        limit.saturating_sub(read),
+                                        e
+                                    ),
                                 )))
                                 .await
                             {
-                                error!("Unable to send error to client while reading {}: {} (Error was:{})", track, e2, e);
+                                error!("Unable to send error to client while reading {}: {} (Error was:{})", track, e3, e);
                             }
                             break;
                         }
-                    };
+                    }
+                    Some(e) => {
+                        if let Err(e2) = tx
+                            .send(Result::<_, Status>::Err(Status::new(
+                                Code::Internal,
+                                format!(
+                                    "Cannot read track, {:} bytes short: {:}",
+                                    limit.saturating_sub(read),
+                                    e
+                                ),
+                            )))
+                            .await
+                        {
+                            error!("Unable to send error to client while reading {}: {} (Error was:{})", track, e2, e);
+                        }
+                        break;
+                    }
                 }
-                info!("Done reading with underrun of {:}...", limit);
-            } else {
-                tx.send(Result::<_, Status>::Err(Status::new(
-                    Code::InvalidArgument,
-                    "No track is currently open",
-                )))
-                .await
-                .unwrap();
             }
+            // Whether the client is done or gone, don't keep downloading for nobody.
+            if let Some(loaded_track) = loader.get_opened(&track) {
+                loaded_track.stop_read_ahead();
+            }
+            info!(
+                "Done reading {} of {} bytes, with an underrun of {}",
+                read,
+                limit,
+                limit.saturating_sub(read)
+            );
         });
 
         let output_stream = ReceiverStream::new(rx);
-        Ok(Response::new(Box::pin(output_stream) as Self::ReadStream))
+        let mut response = Response::new(Box::pin(output_stream) as Self::ReadStream);
+        set_stream_id(&mut response, &stream_id);
+        Ok(response)
     }
     async fn seek(&self, req: Request<SeekRequest>) -> Result<Response<SeekResponse>, Status> {
         let req = req.into_inner();
-        let track = SpotifyId::from_uri(&req.track.unwrap().r#ref)
+        let track = metadata::parse_uri(&req.track.ok_or_else(missing_track)?.r#ref)
             .map_err(|_| Status::new(Code::InvalidArgument, "track id is invalid"))?;
-        let position = req.position;
-
         let lock = Arc::clone(&self.state);
         let state = lock.lock().await;
 
         let loader_lock = Arc::clone(&state.loader);
         let mut loader = loader_lock.lock().await;
 
-        Ok(Response::new(SeekResponse {
-            position: loader.seek(&track, position).map_err(|e| {
-                Status::new(Code::Internal, format!("Couldn't seek in file: {:}", e))
-            })?,
-        }))
+        let position = if req.position_ms > 0 {
+            loader.seek_time(&track, req.position_ms)?
+        } else {
+            loader.seek(&track, req.position)?
+        };
+        Ok(Response::new(SeekResponse { position }))
     }
     async fn close(&self, req: Request<CloseRequest>) -> Result<Response<CloseResponse>, Status> {
         let req = req.into_inner();
-        let track: SpotifyId = SpotifyId::from_uri(&req.track.unwrap().r#ref)
+        let track: SpotifyId = metadata::parse_uri(&req.track.ok_or_else(missing_track)?.r#ref)
             .map_err(|_| Status::new(Code::InvalidArgument, "track id is invalid"))?;
 
         let lock = Arc::clone(&self.state);
@@ -553,11 +2517,65 @@ impl TrackService for Plugin {
         let loader_lock = Arc::clone(&state.loader);
         let mut loader = loader_lock.lock().await;
 
-        loader
-            .close(&track)
-            .map_err(|e| Status::new(Code::Internal, e))?;
+        loader.close(&track)?;
         Ok(Response::new(CloseResponse {}))
     }
+    async fn close_many(
+        &self,
+        req: Request<CloseManyRequest>,
+    ) -> Result<Response<CloseManyResponse>, Status> {
+        let req = req.into_inner();
+
+        let lock = Arc::clone(&self.state);
+        let state = lock.lock().await;
+
+        let loader_lock = Arc::clone(&state.loader);
+        let mut loader = loader_lock.lock().await;
+
+        let tracks = req
+            .tracks
+            .into_iter()
+            .map(|track| {
+                let result = metadata::parse_uri(&track.r#ref)
+                    .map_err(|_| "track id is invalid".to_owned())
+                    .and_then(|id| loader.close(&id).map_err(|e| e.to_string()));
+                ClosedTrack {
+                    r#ref: track.r#ref,
+                    error: result.err().unwrap_or_default(),
+                }
+            })
+            .collect();
+        Ok(Response::new(CloseManyResponse { tracks }))
+    }
+    async fn list_opened(
+        &self,
+        _: Request<ListOpenedRequest>,
+    ) -> Result<Response<ListOpenedResponse>, Status> {
+        if !cfg!(feature = "debug-rpc") {
+            return Err(Status::new(
+                Code::Unimplemented,
+                "plugin was built without the debug-rpc feature",
+            ));
+        }
+
+        let lock = Arc::clone(&self.state);
+        let state = lock.lock().await;
+
+        let loader_lock = Arc::clone(&state.loader);
+        let loader = loader_lock.lock().await;
+
+        Ok(Response::new(ListOpenedResponse {
+            tracks: loader
+                .opened()
+                .map(|(id, track)| OpenedTrackInfo {
+                    r#ref: id.to_string(),
+                    ref_count: track.ref_count().into(),
+                    length: track.len() as u64,
+                    format: format!("{:?}", track.format()),
+                })
+                .collect(),
+        }))
+    }
 }
 
 #[tonic::async_trait]
@@ -569,78 +2587,413 @@ impl TracklistService for Plugin {
     ) -> Result<Response<Self::FetchContentStream>, Status> {
         let args = req.into_inner();
 
-        let plist_uri = SpotifyId::from_uri(&args.tracklist.unwrap().r#ref).map_err(|e| {
-            Status::new(
-                Code::InvalidArgument,
-                format!("Couldn't parse the playlist id: {:}", e),
-            )
-        })?;
+        let tracklist_uri = TracklistRef::parse(&args.tracklist.unwrap_or_default().r#ref)
+            .map_err(|e| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("Couldn't parse the tracklist id: {:}", e),
+                )
+            })?;
+
+        let market = parse_market(&args.market)?;
 
         let (tx, rx) = mpsc::channel(4);
+        let page_size = self.config.default_page_size;
+        let full_metadata = args.full_metadata;
+        let prewarm_count = self.config.prewarm_tracks;
+        let prefer_cached = self.config.prefer_cached_alternative;
+        let formats = self.config.format_preference();
+        // Keys are prewarmed for the files available in the market the tracklist is fetched in.
+        let loader_market = market.clone().or_else(|| self.config.market.clone());
+        let artwork = Arc::clone(&self.artwork);
+        let stream = self.register_stream(args.stream_id)?;
+        let stream_id = stream.id().to_owned();
 
         let lock = Arc::clone(&self.state);
         tokio::spawn(async move {
-            let state = lock.lock().await;
-
-            let plist = librespot_metadata::Playlist::get(&state.session, &plist_uri)
-                .await
-                .unwrap();
-            info!("{:?}", plist);
-
-            let tracks: Vec<_> = plist.tracks().collect();
-            let offset = args.offset;
-            let mut limit = args.limit;
-
-            limit = if limit > 0 {
-                limit + offset
+            let mut state = lock.lock().await;
+            state.revive().await;
+
+            let offset = cmp::max(args.offset, 0) as usize;
+            let end = if args.limit > 0 {
+                Some(offset.saturating_add(args.limit as usize))
+            } else if page_size > 0 {
+                Some(offset.saturating_add(page_size))
             } else {
-                tracks.len() as i32
+                None
             };
-
-            for i in offset..limit {
-                let track_id = tracks.get(i as usize).unwrap();
-                let track = librespot_metadata::Track::get(&state.session, track_id)
-                    .await
-                    .unwrap();
-                info!("track: {} ", track.name);
-                match tx.send(Result::<Track, Status>::Ok(track.into())).await {
-                    Ok(_) => {
-                        // item (server response) was queued to be send to client
+            let market = market.unwrap_or_else(|| state.market());
+            // Tracks are listed page by page from `offset`, each served before the next one is
+            // fetched.
+            let mut pages =
+                metadata::TracklistPages::new(&state.session, tracklist_uri, &market, offset, end);
+            let mut occurrences = HashMap::new();
+            let mut prewarm_count = prewarm_count;
+            loop {
+                let (first, items) = match pages.next().await {
+                    Ok(Some(page)) => page,
+                    Ok(None) => return,
+                    Err(e) => {
+                        if let Err(e2) = tx
+                            .send(Result::<Track, Status>::Err(Status::new(
+                                Code::Unavailable,
+                                format!("unable to get tracklist: {:}", e),
+                            )))
+                            .await
+                        {
+                            error!("Unable to send error to client: {}", e2);
+                        }
+                        return;
                     }
-                    Err(_item) => {
-                        // output_stream was build from rx and both are dropped
+                };
+                let listed = first + items.len();
+                let keys: Vec<String> = items
+                    .iter()
+                    .map(|item| ordering_key(&mut occurrences, item))
+                    .collect();
+                // Past the end of the tracklist, the page is simply empty.
+                let page = cmp::max(offset, first)..cmp::min(end.unwrap_or(listed), listed);
+
+                // The first tracks of the page are the likeliest to be played next, so request
+                // their keys in the background for them to open faster.
+                if prewarm_count > 0 && !page.is_empty() {
+                    let session = state.session.clone();
+                    let loader = Arc::clone(&state.loader);
+                    let (formats, loader_market) = (formats.clone(), loader_market.clone());
+                    let upcoming: Vec<SpotifyId> = page
+                        .clone()
+                        .take(prewarm_count)
+                        .map(|position| items[position - first].id)
+                        .collect();
+                    prewarm_count -= upcoming.len();
+                    tokio::spawn(async move {
+                        let Some(offline) = loader.lock().await.offline().cloned() else {
+                            return;
+                        };
+                        let key_count = audio::loader::prewarm_keys(
+                            &session,
+                            &offline,
+                            &upcoming,
+                            prefer_cached,
+                            &formats,
+                            loader_market.as_deref(),
+                        )
+                        .await;
+                        debug!(
+                            "Prewarmed {} of the next {} audio keys",
+                            key_count,
+                            upcoming.len()
+                        );
+                    });
+                }
+
+                // Tracks are resolved concurrently, but still sent in the tracklist order.
+                let session = &state.session;
+                let (items, market, artwork) = (&items, &market, &artwork);
+                let mut tracks = stream::iter(page)
+                    .map(|position| async move {
+                        let mut track =
+                            get_item(session, &items[position - first].id, market).await;
+                        if full_metadata {
+                            if let Ok(track) = &mut track {
+                                fill_artwork(session, artwork, track).await;
+                            }
+                        }
+                        (position, track)
+                    })
+                    .buffered(METADATA_CONCURRENCY);
+
+                while let Some((position, track)) = tracks.next().await {
+                    if stream.is_cancelled() {
+                        info!("Fetching {} cancelled at {}", tracklist_uri, position);
+                        if let Err(e) = tx
+                            .send(Result::<Track, Status>::Err(Status::new(
+                                Code::Cancelled,
+                                "Fetch was cancelled",
+                            )))
+                            .await
+                        {
+                            debug!("Unable to notify the cancellation: {}", e);
+                        }
                         return;
                     }
+                    let item = &items[position - first];
+                    // An unavailable or region-locked track shouldn't cut the rest of the page.
+                    let mut track = match track {
+                        Ok(track) => track,
+                        Err(e) => {
+                            warn!("Skipping {} of {}: {}", item.id, tracklist_uri, e);
+                            continue;
+                        }
+                    };
+                    track.position = position as i32;
+                    track.ordering_key = keys[position - first].clone();
+                    track.added_at_ms = item.added_at;
+                    track.added_by = item.added_by.clone();
+                    info!("track: {} ", track.title.escape_debug());
+                    match tx.send(Result::<Track, Status>::Ok(track)).await {
+                        Ok(_) => {
+                            // item (server response) was queued to be send to client
+                        }
+                        Err(_item) => {
+                            // output_stream was build from rx and both are dropped
+                            return;
+                        }
+                    }
                 }
             }
         });
 
         let output_stream = ReceiverStream::new(rx);
-        Ok(Response::new(
-            Box::pin(output_stream) as Self::FetchContentStream
-        ))
+        let mut response = Response::new(Box::pin(output_stream) as Self::FetchContentStream);
+        set_stream_id(&mut response, &stream_id);
+        Ok(response)
+    }
+    async fn prewarm_keys(
+        &self,
+        req: Request<PrewarmKeysRequest>,
+    ) -> Result<Response<PrewarmKeysResponse>, Status> {
+        let args = req.into_inner();
+
+        let tracklist_uri = TracklistRef::parse(&args.tracklist.unwrap_or_default().r#ref)
+            .map_err(|e| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("Couldn't parse the tracklist id: {:}", e),
+                )
+            })?;
+        let request_market = parse_market(&args.market)?;
+
+        // Don't hold the state while requesting the keys, as it can take a while.
+        let (session, offline, market) = {
+            let state = self.state.lock().await;
+            let offline = state.loader.lock().await.offline().cloned();
+            let market = request_market.clone().unwrap_or_else(|| state.market());
+            (state.session.clone(), offline, market)
+        };
+        let offline = offline.ok_or_else(|| {
+            Status::new(
+                Code::FailedPrecondition,
+                "no offline index to store the keys in",
+            )
+        })?;
+
+        let tracks = metadata::tracklist_items(&session, &tracklist_uri, &market)
+            .await
+            .map_err(|e| {
+                Status::new(
+                    Code::Unavailable,
+                    format!("unable to get tracklist: {:}", e),
+                )
+            })?;
+        let tracks = &tracks[..cmp::min(tracks.len(), MAX_PREWARM_TRACKS)];
+
+        let key_count = audio::loader::prewarm_keys(
+            &session,
+            &offline,
+            tracks,
+            self.config.prefer_cached_alternative,
+            &self.config.format_preference(),
+            request_market.as_deref().or(self.config.market.as_deref()),
+        )
+        .await;
+        info!(
+            "Prewarmed {} of {} audio keys for {}",
+            key_count,
+            tracks.len(),
+            tracklist_uri
+        );
+        Ok(Response::new(PrewarmKeysResponse {
+            key_count: key_count as i32,
+            track_count: tracks.len() as i32,
+        }))
+    }
+    async fn get_duration(
+        &self,
+        req: Request<TracklistDurationRequest>,
+    ) -> Result<Response<TracklistDurationResponse>, Status> {
+        let args = req.into_inner();
+
+        let tracklist_uri = TracklistRef::parse(&args.tracklist.unwrap_or_default().r#ref)
+            .map_err(|e| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("Couldn't parse the tracklist id: {:}", e),
+                )
+            })?;
+
+        // The state isn't held while resolving the tracks, for the other requests not to wait on
+        // it.
+        let (session, market) = {
+            let state = self.state.lock().await;
+            (state.session.clone(), state.market())
+        };
+        let tracks = metadata::tracklist_items(&session, &tracklist_uri, &market)
+            .await
+            .map_err(|e| {
+                Status::new(
+                    Code::Unavailable,
+                    format!("unable to get tracklist: {:}", e),
+                )
+            })?;
+
+        // Only the first tracks are looked up, so huge tracklists report a partial duration.
+        let durations: Vec<i64> = stream::iter(tracks.iter().take(MAX_DURATION_TRACKS))
+            .map(|track_id| get_item(&session, track_id, &market))
+            .buffered(METADATA_CONCURRENCY)
+            .filter_map(|track| future::ready(track.ok().map(|t| t.duration_ms as i64)))
+            .collect()
+            .await;
+
+        Ok(Response::new(TracklistDurationResponse {
+            duration_ms: durations.iter().sum(),
+            track_count: durations.len() as i32,
+            complete: durations.len() == tracks.len(),
+        }))
+    }
+    async fn append_to_playlist(
+        &self,
+        req: Request<AppendToPlaylistRequest>,
+    ) -> Result<Response<AppendToPlaylistResponse>, Status> {
+        let args = req.into_inner();
+
+        let playlist = metadata::parse_uri(&args.playlist.unwrap_or_default().r#ref)
+            .ok()
+            .filter(|id| id.item_type == SpotifyItemType::Playlist)
+            .ok_or_else(|| Status::new(Code::InvalidArgument, "Invalid playlist reference"))?;
+        let track = metadata::parse_uri(&args.track.unwrap_or_default().r#ref)
+            .map_err(|_| Status::new(Code::InvalidArgument, "Invalid track reference"))?;
+
+        // Don't hold the state while editing the playlist.
+        let session = {
+            let state = self.state.lock().await;
+            if !matches!(state.status, SessionStatus::Connected(_)) {
+                return Err(Status::new(
+                    Code::Unauthenticated,
+                    "not connected to Spotify",
+                ));
+            }
+            state.session.clone()
+        };
+
+        let head = metadata::playlist_head(&session, &playlist)
+            .await
+            .map_err(|e| {
+                Status::new(Code::Unavailable, format!("unable to get playlist: {:}", e))
+            })?;
+        if !head.writable_by(&session.username()) {
+            return Err(Status::new(
+                Code::PermissionDenied,
+                format!(
+                    "{} is neither owned by the user nor collaborative",
+                    playlist
+                ),
+            ));
+        }
+        let track_count = metadata::append_to_playlist(&session, &playlist, &head, &track)
+            .await
+            .map_err(|e| {
+                Status::new(
+                    Code::Unavailable,
+                    format!("unable to update playlist: {:}", e),
+                )
+            })?;
+        info!("Appended {} to {}", track, playlist);
+        Ok(Response::new(AppendToPlaylistResponse {
+            track_count: track_count as i32,
+        }))
+    }
+}
+
+/// Listen on `path`, replacing a socket left over by a previous run. A socket another instance
+/// is still listening on is left alone.
+async fn bind_socket(path: &Path, retries: u32) -> Result<UnixListener, String> {
+    let mut attempt = 0;
+    loop {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(format!(
+                "{} is in use by another running instance",
+                path.display()
+            ));
+        }
+        if std::fs::remove_file(path).is_ok() {
+            info!("Removing old socket")
+        }
+        let e = match UnixListener::bind(path) {
+            Ok(uds) => return Ok(uds),
+            Err(e) => e,
+        };
+        if attempt >= retries {
+            return Err(format!("Unable to listen on {}: {}", path.display(), e));
+        }
+        attempt += 1;
+        warn!(
+            "Unable to listen on {} (attempt {}/{}): {}",
+            path.display(),
+            attempt,
+            retries + 1,
+            e
+        );
+        tokio::time::sleep(BIND_RETRY_DELAY * attempt).await;
+    }
+}
+
+/// Resolve once the plugin is asked to stop, with SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("Unable to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
-    let path = "/tmp/mixxx_plugin_test.sock";
 
-    if std::fs::remove_file(Path::new(path)).is_ok() {
-        info!("Removing old socket")
+    let mut config = Config::from_env();
+    if let Some(socket) = std::env::args_os().nth(1) {
+        config.socket = PathBuf::from(socket);
+    }
+    let path = config.socket.as_path();
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent).map_err(|e| {
+            format!(
+                "Unable to create the socket directory {}: {}",
+                parent.display(),
+                e
+            )
+        })?;
     }
-    std::fs::create_dir_all(Path::new(path).parent().unwrap())?;
 
-    let plugin: Plugin = Plugin::default();
+    if let Some(proxy) = config
+        .proxy_url()
+        .map_err(|e| format!("Invalid proxy: {}", e))?
+    {
+        // Not the whole URL, which may carry credentials.
+        info!(
+            "Connecting to Spotify through {}:{}",
+            proxy.host_str().unwrap_or_default(),
+            proxy.port_or_known_default().unwrap_or_default()
+        );
+    }
+    let plugin: Plugin = Plugin::new(config.clone());
 
-    AudioFetchParams::set(AudioFetchParams {
-        read_ahead_before_playback: Duration::from_secs(5),
-        read_ahead_during_playback: Duration::from_secs(30),
-        prefetch_threshold_factor: 4.0,
-        ..AudioFetchParams::default()
-    })
-    .map_err(|_| "Unable to set AudioFetchParams")?;
+    let fetch_params = config
+        .fetch_params()
+        .map_err(|e| format!("Invalid audio fetch parameters: {}", e))?;
+    AudioFetchParams::set(fetch_params).map_err(|_| "Unable to set AudioFetchParams")?;
 
     let lock = Arc::clone(&plugin.state);
     tokio::spawn(async move {
@@ -650,21 +3003,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 state.status = match state.session.connect(cred, true).await {
                     Ok(()) => {
                         info!("Connected with cached credentials");
-                        match librespot_metadata::Rootlist::get(
-                            &state.session,
-                            &SpotifyId {
-                                id: 0,
-                                item_type: SpotifyItemType::Unknown,
-                            },
-                        )
-                        .await
-                        {
-                            Ok(rootlist) => SessionStatus::Connected(Box::new(rootlist)),
-                            Err(e) => {
-                                error!("Cannot fetch rootlist with cached credentials: {:}", e);
-                                SessionStatus::Failed(e.error.to_string())
-                            }
-                        }
+                        SessionStatus::Connected(fetch_rootlist(&state.session).await)
                     }
                     Err(e) => {
                         error!("Cannot connect with cached credentials: {:}", e);
@@ -675,15 +3014,161 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
-    let uds = UnixListener::bind(path)?;
-    let uds_stream = UnixListenerStream::new(uds);
+    {
+        let plugin = plugin.clone();
+        tokio::spawn(async move { plugin.watch_session().await });
+    }
+
+    if let Some(timeout) = config.idle_timeout {
+        let plugin = plugin.clone();
+        tokio::spawn(async move { plugin.watch_idle(timeout).await });
+    }
+
+    let uds = bind_socket(path, config.bind_retries).await?;
+    info!("Listening on {}", path.display());
+    // A failed accept only concerns that connection, so log it and keep serving the others.
+    let uds_stream = UnixListenerStream::new(uds).filter_map(|conn| async move {
+        match conn {
+            Ok(conn) => Some(Ok::<_, std::io::Error>(conn)),
+            Err(e) => {
+                warn!("Unable to accept connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                None
+            }
+        }
+    });
+
+    let activity = {
+        let plugin = plugin.clone();
+        move |req: Request<()>| {
+            plugin.touch();
+            Ok(req)
+        }
+    };
+
+    let mut server = Server::builder()
+        .http2_keepalive_interval(config.http2_keepalive_interval)
+        .http2_keepalive_timeout(config.http2_keepalive_timeout)
+        .tcp_keepalive(config.tcp_keepalive);
+    if let Some(timeout) = config.request_timeout {
+        server = server.timeout(timeout);
+    }
 
-    Server::builder()
-        .add_service(TrackServiceServer::new(plugin.clone()))
-        .add_service(TracklistServiceServer::new(plugin.clone()))
-        .add_service(PluginServiceServer::new(plugin))
-        .serve_with_incoming(uds_stream)
+    server
+        .add_service(TrackServiceServer::with_interceptor(
+            plugin.clone(),
+            activity.clone(),
+        ))
+        .add_service(TracklistServiceServer::with_interceptor(
+            plugin.clone(),
+            activity.clone(),
+        ))
+        .add_service(PluginServiceServer::with_interceptor(
+            plugin.clone(),
+            activity,
+        ))
+        // New connections are refused once signaled, and the requests in flight are awaited,
+        // which the cancelled streams end right away.
+        .serve_with_incoming_shutdown(uds_stream, async {
+            shutdown_signal().await;
+            plugin.shutdown().await;
+        })
         .await?;
 
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!("Unable to remove the socket {}: {}", path.display(), e);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACK: &str = "spotify:track:4uLU6hMCjMI75M1A2tKUQC";
+
+    /// A plugin without cache nor session, whose tracks are opened as placeholders.
+    fn test_plugin() -> Plugin {
+        Plugin::new(Config {
+            cache_dir: None,
+            credentials_dir: None,
+            ..Config::default()
+        })
+    }
+
+    /// Open a placeholder of `duration_ms` in place of `track`, returning its size.
+    async fn open_placeholder(plugin: &Plugin, track: &str, duration_ms: u32) -> usize {
+        let state = plugin.state.lock().await;
+        let mut loader = state.loader.lock().await;
+        loader
+            .open_placeholder(metadata::parse_uri(track).unwrap(), duration_ms)
+            .len()
+    }
+
+    async fn read(
+        plugin: &Plugin,
+        track: &str,
+        offset: u64,
+        limit: u64,
+        chunk_size: u32,
+    ) -> Vec<ReadChunk> {
+        let request = ReadRequest {
+            track: Some(Track {
+                r#ref: track.to_owned(),
+                ..Track::default()
+            }),
+            offset,
+            limit,
+            chunk_size,
+            ..ReadRequest::default()
+        };
+        plugin
+            .read(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await
+    }
+
+    fn total_len(chunks: &[ReadChunk]) -> usize {
+        chunks.iter().map(|chunk| chunk.data.len()).sum()
+    }
+
+    #[tokio::test]
+    async fn read_limit_below_chunk_size() {
+        let plugin = test_plugin();
+        open_placeholder(&plugin, TRACK, 1_000).await;
+
+        let chunks = read(&plugin, TRACK, 0, 100, 1_024).await;
+        assert_eq!(total_len(&chunks), 100);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].end, ReadEnd::Limit as i32);
+        assert_eq!(chunks[0].underrun, 0);
+    }
+
+    #[tokio::test]
+    async fn read_limit_not_multiple_of_chunk_size() {
+        let plugin = test_plugin();
+        open_placeholder(&plugin, TRACK, 1_000).await;
+
+        let chunks = read(&plugin, TRACK, 10, 1_000, 128).await;
+        assert_eq!(total_len(&chunks), 1_000);
+        assert!(chunks.iter().all(|chunk| chunk.data.len() <= 128));
+        assert_eq!(chunks.last().unwrap().data.len(), 1_000 % 128);
+        assert_eq!(chunks.last().unwrap().end, ReadEnd::Limit as i32);
+    }
+
+    #[tokio::test]
+    async fn read_limit_past_the_end() {
+        let plugin = test_plugin();
+        let len = open_placeholder(&plugin, TRACK, 1_000).await;
+
+        let chunks = read(&plugin, TRACK, len as u64 - 50, 1_000, 128).await;
+        assert_eq!(total_len(&chunks), 50);
+        let last = chunks.last().unwrap();
+        assert_eq!(last.end, ReadEnd::Eof as i32);
+        assert_eq!(last.underrun, 950);
+    }
+}
@@ -0,0 +1,649 @@
+use crate::{collection, history};
+
+use http::Method;
+use librespot_core::{spotify_id::SpotifyItemType, Error, FileId, Session, SpotifyId};
+use librespot_metadata::{
+    restriction::Restrictions, Album, Artist, Episode, Metadata, Playlist, Rootlist, Show, Track,
+};
+use librespot_protocol::playlist4_external::{
+    op, Add, Delta, Item, ListAttributes, ListChanges, Op, SelectedListContent,
+};
+use log::debug;
+use protobuf::{Message, MessageField};
+use serde::Deserialize;
+use std::{cmp, fmt};
+
+/// Whether the restrictions allow playback in the given market.
+pub fn available_in(restrictions: &Restrictions, market: &str) -> bool {
+    restrictions.iter().all(|restriction| {
+        let allowed = restriction
+            .countries_allowed
+            .as_ref()
+            .map_or(true, |countries| {
+                countries.iter().any(|c| c.eq_ignore_ascii_case(market))
+            });
+        let forbidden = restriction
+            .countries_forbidden
+            .as_ref()
+            .is_some_and(|countries| countries.iter().any(|c| c.eq_ignore_ascii_case(market)));
+        allowed && !forbidden
+    })
+}
+
+/// Whether the logged in account is a premium one, or `None` when Spotify didn't tell yet.
+pub fn has_premium(session: &Session) -> Option<bool> {
+    session
+        .get_user_attribute("type")
+        .map(|account_type| account_type == "premium")
+}
+
+/// Whether the account may play explicit content, which parental controls can filter.
+pub fn explicit_content_allowed(session: &Session) -> bool {
+    session
+        .get_user_attribute("filter-explicit-content")
+        .map_or(true, |filter| filter != "1")
+}
+
+/// Fetch a track's metadata as the user sees it in `market`: when the track isn't available
+/// there, Spotify relinks it to one of its alternatives, whose title and album are used instead.
+/// The original id is kept so refs remain stable.
+pub async fn get_track_in_market(
+    session: &Session,
+    id: &SpotifyId,
+    market: &str,
+) -> Result<Track, Error> {
+    let track = Track::get(session, id).await?;
+    if market.is_empty() || available_in(&track.restrictions, market) {
+        return Ok(track);
+    }
+
+    for alternative in track.alternatives.iter() {
+        match Track::get(session, alternative).await {
+            Ok(relinked) if available_in(&relinked.restrictions, market) => {
+                return Ok(Track {
+                    id: track.id,
+                    ..relinked
+                });
+            }
+            Ok(_) => {}
+            Err(e) => debug!("Cannot fetch alternative {}: {}", alternative, e),
+        }
+    }
+    Ok(track)
+}
+
+/// A tracklist: either a Spotify item or one of the user's collections or play history, which
+/// have no uri and are referred to by their browse node id instead.
+#[derive(Clone, Copy)]
+pub enum TracklistRef {
+    Item(SpotifyId),
+    SavedEpisodes,
+    LikedSongs,
+    RecentlyPlayed,
+}
+
+impl TracklistRef {
+    pub fn parse(tracklist_ref: &str) -> Result<Self, Error> {
+        match tracklist_ref {
+            SAVED_EPISODES_REF => Ok(TracklistRef::SavedEpisodes),
+            LIKED_SONGS_REF => Ok(TracklistRef::LikedSongs),
+            RECENTLY_PLAYED_REF => Ok(TracklistRef::RecentlyPlayed),
+            _ => parse_uri(tracklist_ref).map(TracklistRef::Item),
+        }
+    }
+}
+
+impl fmt::Display for TracklistRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TracklistRef::Item(id) => id.fmt(f),
+            TracklistRef::SavedEpisodes => f.write_str(SAVED_EPISODES_REF),
+            TracklistRef::LikedSongs => f.write_str(LIKED_SONGS_REF),
+            TracklistRef::RecentlyPlayed => f.write_str(RECENTLY_PLAYED_REF),
+        }
+    }
+}
+
+/// An entry of a tracklist. Only playlists record when and by whom their entries were added.
+pub struct TracklistItem {
+    pub id: SpotifyId,
+    /// Milliseconds since the Unix epoch, `0` when unknown.
+    pub added_at: i64,
+    pub added_by: String,
+    /// Hex id of the playlist entry, which stays the same as entries are added or moved. Only
+    /// known for playlists listed by page, empty otherwise.
+    pub uid: String,
+}
+
+impl From<SpotifyId> for TracklistItem {
+    fn from(id: SpotifyId) -> Self {
+        TracklistItem {
+            id,
+            added_at: 0,
+            added_by: String::new(),
+            uid: String::new(),
+        }
+    }
+}
+
+/// List the items of anything that can be browsed as a tracklist: playlists, albums, shows,
+/// artists and the user's collections. The top tracks of artists are those of `market`.
+pub async fn tracklist_items(
+    session: &Session,
+    tracklist: &TracklistRef,
+    market: &str,
+) -> Result<Vec<SpotifyId>, Error> {
+    Ok(tracklist_items_until(session, tracklist, market, None)
+        .await?
+        .into_iter()
+        .map(|item| item.id)
+        .collect())
+}
+
+/// Same as `tracklist_items`, but may stop listing the user's collections, which can be huge,
+/// after the first `end` items.
+pub async fn tracklist_items_until(
+    session: &Session,
+    tracklist: &TracklistRef,
+    market: &str,
+    end: Option<usize>,
+) -> Result<Vec<TracklistItem>, Error> {
+    let ids = match tracklist {
+        TracklistRef::Item(id) if id.item_type == SpotifyItemType::Playlist => {
+            return Ok(Playlist::get(session, id)
+                .await?
+                .contents
+                .items
+                .iter()
+                .map(|item| TracklistItem {
+                    id: item.id,
+                    added_at: item.attributes.timestamp.as_timestamp_ms(),
+                    added_by: item.attributes.added_by.clone(),
+                    uid: String::new(),
+                })
+                .collect())
+        }
+        TracklistRef::Item(id) => match id.item_type {
+            SpotifyItemType::Album => Album::get(session, id).await?.tracks().copied().collect(),
+            SpotifyItemType::Show => Show::get(session, id).await?.episodes.to_vec(),
+            SpotifyItemType::Artist => Artist::get(session, id)
+                .await?
+                .top_tracks
+                .for_country(market)
+                .to_vec(),
+            _ => return Err(Error::unimplemented(format!("{} is not a tracklist", id))),
+        },
+        TracklistRef::SavedEpisodes => {
+            collection::collection_items(
+                session,
+                collection::SAVED_EPISODES,
+                SpotifyItemType::Episode,
+                end,
+            )
+            .await?
+        }
+        TracklistRef::LikedSongs => {
+            collection::collection_items(
+                session,
+                collection::LIKED_SONGS,
+                SpotifyItemType::Track,
+                end,
+            )
+            .await?
+        }
+        TracklistRef::RecentlyPlayed => history::recently_played(session, end).await?,
+    };
+    Ok(ids.into_iter().map(TracklistItem::from).collect())
+}
+
+// Playlists are listed by pages of this many items, when served progressively.
+const PLAYLIST_PAGE_SIZE: usize = 100;
+const PLAYLIST_ENDPOINT: &str = "/playlist/v2/playlist";
+
+/// List the items `[from, from + length)` of a playlist, fewer past its end, without fetching
+/// the rest of it.
+pub async fn playlist_items(
+    session: &Session,
+    id: &SpotifyId,
+    from: usize,
+    length: usize,
+) -> Result<Vec<TracklistItem>, Error> {
+    let endpoint = format!(
+        "{}/{}?from={}&length={}",
+        PLAYLIST_ENDPOINT,
+        id.to_base62()?,
+        from,
+        length
+    );
+    let response = session
+        .spclient()
+        .request(&Method::GET, &endpoint, None, None)
+        .await?;
+    let message = SelectedListContent::parse_from_bytes(&response)?;
+    message
+        .contents
+        .items
+        .iter()
+        .map(|item| {
+            Ok(TracklistItem {
+                id: SpotifyId::from_uri(item.uri())?,
+                added_at: item.attributes.timestamp(),
+                added_by: item.attributes.added_by().to_owned(),
+                uid: encode_hex(item.attributes.item_id()),
+            })
+        })
+        .collect()
+}
+
+/// Lists a tracklist page by page, so its first items can be served before the whole of it is
+/// fetched. Only playlists are actually fetched by page, from any position, other tracklists
+/// come in one go from their start.
+pub struct TracklistPages<'a> {
+    session: &'a Session,
+    tracklist: TracklistRef,
+    market: String,
+    end: Option<usize>,
+    listed: usize,
+    done: bool,
+}
+
+impl<'a> TracklistPages<'a> {
+    /// List `tracklist` from its item at `start`, stopping before `end` when given. Tracklists
+    /// which aren't playlists are still listed from their first item.
+    pub fn new(
+        session: &'a Session,
+        tracklist: TracklistRef,
+        market: &str,
+        start: usize,
+        end: Option<usize>,
+    ) -> Self {
+        Self {
+            session,
+            tracklist,
+            market: market.to_owned(),
+            end,
+            listed: start,
+            done: false,
+        }
+    }
+    /// The next items of the tracklist along with the position of the first one, `None` once
+    /// all of them are listed.
+    pub async fn next(&mut self) -> Result<Option<(usize, Vec<TracklistItem>)>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+        let playlist = match self.tracklist {
+            TracklistRef::Item(id) if id.item_type == SpotifyItemType::Playlist => id,
+            _ => {
+                self.done = true;
+                let items =
+                    tracklist_items_until(self.session, &self.tracklist, &self.market, self.end)
+                        .await?;
+                return Ok(Some((0, items)));
+            }
+        };
+        let length = match self.end {
+            Some(end) => cmp::min(PLAYLIST_PAGE_SIZE, end.saturating_sub(self.listed)),
+            None => PLAYLIST_PAGE_SIZE,
+        };
+        if length == 0 {
+            self.done = true;
+            return Ok(None);
+        }
+        let first = self.listed;
+        let items = playlist_items(self.session, &playlist, first, length).await?;
+        self.listed += items.len();
+        // A short page is the last one.
+        self.done = items.len() < length;
+        Ok(Some((first, items)).filter(|(_, items)| !items.is_empty()))
+    }
+}
+
+/// What is needed to know whether a playlist can be edited, and to edit it.
+pub struct PlaylistHead {
+    pub owner: String,
+    pub collaborative: bool,
+    pub length: usize,
+    revision: Vec<u8>,
+}
+
+impl PlaylistHead {
+    /// Whether `username` may add items to the playlist.
+    pub fn writable_by(&self, username: &str) -> bool {
+        self.collaborative || self.owner == username
+    }
+}
+
+/// Fetch the owner, attributes and revision of a playlist, without its items.
+pub async fn playlist_head(session: &Session, id: &SpotifyId) -> Result<PlaylistHead, Error> {
+    let endpoint = format!("{}/{}?from=0&length=0", PLAYLIST_ENDPOINT, id.to_base62()?);
+    let response = session
+        .spclient()
+        .request(&Method::GET, &endpoint, None, None)
+        .await?;
+    let message = SelectedListContent::parse_from_bytes(&response)?;
+    Ok(PlaylistHead {
+        owner: message.owner_username().to_owned(),
+        collaborative: message.attributes.collaborative(),
+        length: message.length() as usize,
+        revision: message.revision().to_vec(),
+    })
+}
+
+/// Add `track` at the end of a playlist, on top of the revision `head` was fetched at. Returns
+/// the new number of items in the playlist, as reported back by Spotify or fetched again when it
+/// isn't, since others may have edited the playlist meanwhile.
+pub async fn append_to_playlist(
+    session: &Session,
+    id: &SpotifyId,
+    head: &PlaylistHead,
+    track: &SpotifyId,
+) -> Result<usize, Error> {
+    let mut item = Item::new();
+    item.set_uri(track.to_uri()?);
+    let mut add = Add::new();
+    add.items.push(item);
+    add.set_add_last(true);
+    let mut operation = Op::new();
+    operation.set_kind(op::Kind::ADD);
+    operation.add = MessageField::some(add);
+    let mut delta = Delta::new();
+    delta.ops.push(operation);
+    let mut changes = ListChanges::new();
+    changes.set_base_revision(head.revision.clone());
+    changes.deltas.push(delta);
+
+    let endpoint = format!("{}/{}/changes", PLAYLIST_ENDPOINT, id.to_base62()?);
+    let response = session
+        .spclient()
+        .request_with_protobuf(&Method::POST, &endpoint, None, &changes)
+        .await?;
+    match SelectedListContent::parse_from_bytes(&response) {
+        Ok(message) if message.has_length() => Ok(message.length() as usize),
+        _ => Ok(playlist_head(session, id).await?.length),
+    }
+}
+
+// Markers framing the folders of the rootlist, followed by the folder id and, when starting it,
+// its url-encoded name.
+const FOLDER_START: &str = "spotify:start-group:";
+const FOLDER_END: &str = "spotify:end-group:";
+
+/// A folder of playlists in the user's library.
+#[derive(Clone)]
+pub struct PlaylistFolder {
+    pub id: String,
+    pub name: String,
+}
+
+/// A playlist of the user's library, along with the folders it is nested in, outermost first.
+pub struct LibraryPlaylist {
+    pub id: SpotifyId,
+    pub name: String,
+    pub owner: String,
+    pub folders: Vec<PlaylistFolder>,
+    /// Cover image, in its thumbnail size when Spotify offers one.
+    pub picture: Option<FileId>,
+}
+
+/// The cover image of a playlist, preferring its thumbnail. Sized pictures are only given by
+/// url, whose last segment is the image id.
+fn playlist_picture(attributes: &ListAttributes) -> Option<FileId> {
+    attributes
+        .picture_size
+        .iter()
+        .find(|size| size.target_name() == "small")
+        .and_then(|size| size.url().rsplit('/').next())
+        .and_then(parse_file_id)
+        .or_else(|| {
+            Some(attributes.picture())
+                .filter(|picture| picture.len() == 20)
+                .map(FileId::from_raw)
+        })
+}
+
+/// List the playlists of the user's library. librespot drops the folder markers of the
+/// rootlist, so it is parsed from the raw message instead.
+pub async fn library_playlists(session: &Session) -> Result<Vec<LibraryPlaylist>, Error> {
+    let response = Rootlist::request(
+        session,
+        &SpotifyId {
+            id: 0,
+            item_type: SpotifyItemType::Unknown,
+        },
+    )
+    .await?;
+    let message = SelectedListContent::parse_from_bytes(&response)?;
+
+    let mut folders: Vec<PlaylistFolder> = vec![];
+    let mut playlists = vec![];
+    for (index, item) in message.contents.items.iter().enumerate() {
+        let uri = item.uri();
+        if let Some(folder) = uri.strip_prefix(FOLDER_START) {
+            let (id, name) = folder.split_once(':').unwrap_or((folder, ""));
+            folders.push(PlaylistFolder {
+                id: id.to_owned(),
+                name: url::form_urlencoded::parse(name.as_bytes())
+                    .next()
+                    .map(|(name, _)| name.into_owned())
+                    .unwrap_or_default(),
+            });
+        } else if uri.starts_with(FOLDER_END) {
+            folders.pop();
+        } else {
+            let id = match parse_uri(uri) {
+                Ok(id) => id,
+                Err(e) => {
+                    debug!("Ignoring rootlist item {}: {}", uri, e);
+                    continue;
+                }
+            };
+            let meta = message.contents.meta_items.get(index);
+            playlists.push(LibraryPlaylist {
+                id,
+                name: meta
+                    .map(|meta| meta.attributes.name().to_owned())
+                    .unwrap_or_default(),
+                owner: meta
+                    .map(|meta| meta.owner_username().to_owned())
+                    .unwrap_or_default(),
+                folders: folders.clone(),
+                picture: meta.and_then(|meta| playlist_picture(&meta.attributes)),
+            });
+        }
+    }
+    Ok(playlists)
+}
+
+/// Resolve the show an episode belongs to. librespot only keeps the show name on its
+/// `Episode`, so the id is read from the raw metadata message instead.
+pub async fn episode_show(session: &Session, id: &SpotifyId) -> Result<SpotifyId, Error> {
+    let response = Episode::request(session, id).await?;
+    let message = <Episode as Metadata>::Message::parse_from_bytes(&response)?;
+    let gid = message
+        .show
+        .gid
+        .as_deref()
+        .ok_or_else(|| Error::not_found(format!("no show found for {}", id)))?;
+    Ok(SpotifyId {
+        item_type: SpotifyItemType::Show,
+        ..SpotifyId::from_raw(gid)?
+    })
+}
+
+// Browse node and tracklist ref of the user's saved episodes.
+pub const SAVED_EPISODES_REF: &str = "/savedepisodes/";
+// Browse node and tracklist ref of the user's liked songs.
+pub const LIKED_SONGS_REF: &str = "/likedsongs/";
+// Browse node and tracklist ref of the tracks recently played by the user.
+pub const RECENTLY_PLAYED_REF: &str = "/recent/";
+
+#[derive(Deserialize)]
+struct AudioAnalysis {
+    track: AnalysisSummary,
+}
+
+#[derive(Deserialize)]
+struct AnalysisSummary {
+    duration: f64,
+    #[serde(default)]
+    end_of_fade_in: f64,
+    #[serde(default)]
+    start_of_fade_out: f64,
+}
+
+/// Length of the fade in and fade out of a track in milliseconds, from its audio analysis.
+pub async fn get_fades(session: &Session, id: &SpotifyId) -> Result<(i32, i32), Error> {
+    let data = session.spclient().get_audio_analysis(id).await?;
+    let analysis: AudioAnalysis = serde_json::from_slice(&data)
+        .map_err(|e| Error::failed_precondition(format!("invalid audio analysis: {}", e)))?;
+    let summary = analysis.track;
+    // The fade out starts at the end of the track when there is none.
+    let fade_in = summary.end_of_fade_in.max(0.0);
+    let fade_out = (summary.duration - summary.start_of_fade_out).max(0.0);
+    Ok(((fade_in * 1000.0) as i32, (fade_out * 1000.0) as i32))
+}
+
+// Host of the links shared from the Spotify apps.
+const WEB_PLAYER_HOST: &str = "open.spotify.com/";
+
+/// Parse a ref received from a client. Spotify appends revisions or sharing parameters to
+/// some uris, e.g. `spotify:playlist:<id>:rev:<revision>` or `spotify:track:<id>?si=<share>`,
+/// which `SpotifyId::from_uri` rejects, so only keep the item type and id. Links copied from
+/// the Spotify apps, e.g. `https://open.spotify.com/track/<id>?si=<share>`, are accepted too.
+pub fn parse_uri(uri: &str) -> Result<SpotifyId, Error> {
+    let uri = uri.trim().trim_matches('/');
+    let uri = uri.split(['?', '#', '@']).next().unwrap_or_default();
+    let web_path = ["https://", "http://", ""]
+        .iter()
+        .find_map(|scheme| uri.strip_prefix(scheme)?.strip_prefix(WEB_PLAYER_HOST));
+    if let Some(path) = web_path {
+        // The item type and id come last, after an optional locale or owner.
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        if let [.., item_type, id] = segments.as_slice() {
+            return SpotifyId::from_uri(&format!("spotify:{}:{}", item_type, id));
+        }
+    }
+    let parts: Vec<&str> = uri.split(':').collect();
+    let uri = match parts.as_slice() {
+        // Legacy playlist uris also carry their owner.
+        ["spotify", "user", _, item_type, id, ..] => format!("spotify:{}:{}", item_type, id),
+        ["spotify", item_type, id, ..] => format!("spotify:{}:{}", item_type, id),
+        _ => uri.to_owned(),
+    };
+    SpotifyId::from_uri(&uri)
+}
+
+pub fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 || !value.is_ascii() {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+        .collect()
+}
+
+pub fn encode_hex(value: &[u8]) -> String {
+    value.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse a hex-encoded file id, such as the image id handed out in `Track.artwork_id`.
+pub fn parse_file_id(id: &str) -> Option<FileId> {
+    if id.len() != 40 {
+        return None;
+    }
+    decode_hex(id).map(|raw| FileId::from_raw(&raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACK: &str = "spotify:track:4uLU6hMCjMI75M1A2tKUQC";
+    const PLAYLIST: &str = "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M";
+
+    #[test]
+    fn parse_uri_strips_suffixes() {
+        let cases = [
+            ("spotify:track:4uLU6hMCjMI75M1A2tKUQC", TRACK),
+            (
+                "spotify:track:4uLU6hMCjMI75M1A2tKUQC?si=a1b2c3d4e5f6",
+                TRACK,
+            ),
+            ("  spotify:track:4uLU6hMCjMI75M1A2tKUQC#0:42 ", TRACK),
+            (
+                "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M:rev:0123abcd",
+                PLAYLIST,
+            ),
+            ("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M@0123abcd", PLAYLIST),
+            (
+                "spotify:user:someone:playlist:37i9dQZF1DXcBWIGoYBM5M",
+                PLAYLIST,
+            ),
+        ];
+        for (uri, expected) in cases {
+            assert_eq!(
+                parse_uri(uri).ok(),
+                SpotifyId::from_uri(expected).ok(),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn parse_uri_accepts_web_links() {
+        let cases = [
+            (
+                "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC",
+                TRACK,
+            ),
+            (
+                "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC?si=a1b2c3d4e5f6",
+                TRACK,
+            ),
+            (
+                "http://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC/",
+                TRACK,
+            ),
+            ("open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC", TRACK),
+            (
+                "https://open.spotify.com/intl-fr/track/4uLU6hMCjMI75M1A2tKUQC",
+                TRACK,
+            ),
+            (
+                "https://open.spotify.com/intl-pt/track/4uLU6hMCjMI75M1A2tKUQC?si=x&nd=1",
+                TRACK,
+            ),
+            (
+                "https://open.spotify.com/user/someone/playlist/37i9dQZF1DXcBWIGoYBM5M",
+                PLAYLIST,
+            ),
+        ];
+        for (uri, expected) in cases {
+            assert_eq!(
+                parse_uri(uri).ok(),
+                SpotifyId::from_uri(expected).ok(),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn parse_uri_rejects_invalid_input() {
+        let cases = [
+            "",
+            "not a uri",
+            "spotify:track:",
+            "spotify:track:not-base62!",
+            "https://open.spotify.com/",
+            "https://open.spotify.com/track/",
+            "https://example.com/track/4uLU6hMCjMI75M1A2tKUQC",
+        ];
+        for uri in cases {
+            assert!(parse_uri(uri).is_err(), "{}", uri);
+        }
+    }
+}
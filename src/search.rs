@@ -0,0 +1,110 @@
+use librespot_core::{Error, Session, SpotifyId};
+use log::debug;
+use serde::Deserialize;
+
+// Search endpoint of the Spotify clients, reachable over Mercury with the session itself.
+const SEARCH_URI: &str = "hm://searchview/km/v4/search/";
+
+#[derive(Clone, Copy, Debug)]
+pub enum SearchKind {
+    Tracks,
+    Albums,
+    Artists,
+    Playlists,
+}
+
+#[derive(Deserialize, Default)]
+struct SearchReply {
+    #[serde(default)]
+    results: SearchResults,
+}
+
+#[derive(Deserialize, Default)]
+struct SearchResults {
+    #[serde(default)]
+    tracks: Hits,
+    #[serde(default)]
+    albums: Hits,
+    #[serde(default)]
+    artists: Hits,
+    #[serde(default)]
+    playlists: Hits,
+}
+
+#[derive(Deserialize, Default)]
+struct Hits {
+    #[serde(default)]
+    hits: Vec<Hit>,
+}
+
+#[derive(Deserialize)]
+struct Hit {
+    uri: String,
+    #[serde(default)]
+    name: String,
+}
+
+/// An item matching a search.
+pub struct SearchHit {
+    pub id: SpotifyId,
+    pub name: String,
+}
+
+/// Search the catalogue for items of the given kind matching `query`, best matches first.
+pub async fn search(
+    session: &Session,
+    query: &str,
+    kind: SearchKind,
+    offset: usize,
+    limit: usize,
+    market: &str,
+) -> Result<Vec<SearchHit>, Error> {
+    let uri = format!(
+        "{}{}?entityVersion=2&offset={}&limit={}&catalogue=&country={}&username={}",
+        SEARCH_URI,
+        url::form_urlencoded::byte_serialize(query.as_bytes()).collect::<String>(),
+        offset,
+        limit,
+        market,
+        url::form_urlencoded::byte_serialize(session.username().as_bytes()).collect::<String>(),
+    );
+    let response = session.mercury().get(uri)?.await?;
+    let payload = response
+        .payload
+        .first()
+        .ok_or_else(|| Error::unavailable("empty search reply"))?;
+    let reply: SearchReply = serde_json::from_slice(payload)
+        .map_err(|e| Error::failed_precondition(format!("invalid search reply: {}", e)))?;
+
+    let hits = match kind {
+        SearchKind::Tracks => reply.results.tracks,
+        SearchKind::Albums => reply.results.albums,
+        SearchKind::Artists => reply.results.artists,
+        SearchKind::Playlists => reply.results.playlists,
+    };
+    Ok(hits
+        .hits
+        .into_iter()
+        .filter_map(|hit| match SpotifyId::from_uri(&hit.uri) {
+            Ok(id) => Some(SearchHit { id, name: hit.name }),
+            Err(e) => {
+                debug!("Ignoring search hit {}: {}", hit.uri, e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Search the catalogue for tracks matching `query`, best matches first.
+pub async fn search_tracks(
+    session: &Session,
+    query: &str,
+    limit: usize,
+    market: &str,
+) -> Result<Vec<SpotifyId>, Error> {
+    Ok(search(session, query, SearchKind::Tracks, 0, limit, market)
+        .await?
+        .into_iter()
+        .map(|hit| hit.id)
+        .collect())
+}
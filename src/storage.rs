@@ -0,0 +1,121 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use log::{debug, info, warn};
+
+struct CachedFile {
+    path: PathBuf,
+    size: u64,
+    accessed: SystemTime,
+}
+
+// Length of the name of an audio file, being its hex-encoded id without the directory prefix.
+const FILE_ID_NAME_LEN: usize = 38;
+
+/// librespot stores the audio files in two-level directories named after their id, e.g.
+/// `ab/cdef...`, next to the credentials and our own offline index.
+fn audio_files(dir: &Path) -> io::Result<Vec<CachedFile>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_audio_dir = name.len() == 2
+            && name
+                .to_str()
+                .is_some_and(|n| n.chars().all(|c| c.is_ascii_hexdigit()))
+            && entry.file_type()?.is_dir();
+        if !is_audio_dir {
+            continue;
+        }
+        for file in fs::read_dir(entry.path())? {
+            let file = file?;
+            let metadata = file.metadata()?;
+            if metadata.is_file() {
+                files.push(CachedFile {
+                    path: file.path(),
+                    size: metadata.len(),
+                    accessed: metadata
+                        .accessed()
+                        .or_else(|_| metadata.modified())
+                        .unwrap_or(SystemTime::UNIX_EPOCH),
+                });
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Total size of the audio files in the cache.
+pub fn usage(dir: &Path) -> io::Result<u64> {
+    Ok(audio_files(dir)?.iter().map(|file| file.size).sum())
+}
+
+/// Remove the audio files librespot couldn't have written completely: empty ones and those not
+/// named after a file id. Returns the number of files removed and the bytes freed.
+pub fn remove_invalid(dir: &Path) -> io::Result<(u32, u64)> {
+    let (mut removed, mut freed) = (0, 0);
+    for file in audio_files(dir)? {
+        let valid_name = file
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                name.len() == FILE_ID_NAME_LEN && name.chars().all(|c| c.is_ascii_hexdigit())
+            });
+        if valid_name && file.size > 0 {
+            continue;
+        }
+        match fs::remove_file(&file.path) {
+            Ok(()) => {
+                debug!("Removed invalid cached file {:?}", file.path);
+                removed += 1;
+                freed += file.size;
+            }
+            Err(e) => warn!("Unable to remove {:?} from the cache: {}", file.path, e),
+        }
+    }
+    Ok((removed, freed))
+}
+
+/// Remove the least recently used audio files until the cache fits in `target` bytes. Returns
+/// the number of files evicted and the bytes freed.
+pub fn evict(dir: &Path, target: u64) -> io::Result<(u32, u64)> {
+    let mut files = audio_files(dir)?;
+    let mut size: u64 = files.iter().map(|file| file.size).sum();
+    files.sort_by_key(|file| file.accessed);
+
+    let (mut evicted, mut freed) = (0, 0);
+    for file in files {
+        if size <= target {
+            break;
+        }
+        match fs::remove_file(&file.path) {
+            Ok(()) => {
+                debug!(
+                    "Evicted {:?} ({} bytes) from the cache",
+                    file.path, file.size
+                );
+                size -= file.size;
+                freed += file.size;
+                evicted += 1;
+            }
+            Err(e) => warn!("Unable to evict {:?} from the cache: {}", file.path, e),
+        }
+    }
+    info!(
+        "Evicted {} files ({} bytes) from the cache, now using {} bytes",
+        evicted, freed, size
+    );
+    Ok((evicted, freed))
+}
+
+/// Make sure the cache can be stored in `dir`, creating it if needed.
+pub fn check_writable(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(".write-test");
+    fs::write(&probe, [])?;
+    fs::remove_file(probe)
+}
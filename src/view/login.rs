@@ -1,23 +1,77 @@
-use std::{fs::File, io::Read, str};
+use std::{fmt, path::Path};
 
 use librespot_discovery::Credentials;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize)]
 pub struct LoginForm {
+    #[serde(default)]
     username: String,
+    #[serde(default)]
     password: String,
+    /// OAuth access token, used instead of the password when set, as Spotify is phasing out
+    /// password logins for librespot clients.
+    #[serde(default)]
+    token: Option<String>,
+}
+
+impl fmt::Debug for LoginForm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoginForm")
+            .field("username", &self.username)
+            .field("with_token", &self.has_token())
+            .finish_non_exhaustive()
+    }
+}
+
+impl LoginForm {
+    pub fn new(username: String, password: String, token: Option<String>) -> Self {
+        Self {
+            username,
+            password,
+            token,
+        }
+    }
+
+    fn has_token(&self) -> bool {
+        self.token
+            .as_deref()
+            .is_some_and(|token| !token.trim().is_empty())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.has_token() {
+            Ok(())
+        } else if self.username.trim().is_empty() {
+            Err("Username cannot be empty".to_owned())
+        } else if self.password.is_empty() {
+            Err("Password cannot be empty".to_owned())
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl From<LoginForm> for Credentials {
     fn from(val: LoginForm) -> Self {
-        Credentials::with_password(val.username, val.password)
+        match val.token.filter(|token| !token.trim().is_empty()) {
+            Some(token) => Credentials::with_access_token(token.trim()),
+            None => Credentials::with_password(val.username, val.password),
+        }
     }
 }
 
-pub fn get_qml_view() -> Result<String, String> {
-    let mut root_file = File::open("res/qml/main.qml").map_err(|e| e.to_string())?;
-    let mut buf: Vec<u8> = Vec::new();
-    root_file.read_to_end(&mut buf).map_err(|e| e.to_string())?;
-    Ok(str::from_utf8(&buf).map_err(|e| e.to_string())?.to_owned())
+// Path of the login view within the resource directory.
+#[cfg(not(feature = "embed-qml"))]
+const LOGIN_VIEW: &str = "qml/main.qml";
+
+#[cfg(feature = "embed-qml")]
+pub fn get_qml_view(_resource_dir: &Path) -> Result<String, String> {
+    Ok(include_str!("../../res/qml/main.qml").to_owned())
+}
+
+#[cfg(not(feature = "embed-qml"))]
+pub fn get_qml_view(resource_dir: &Path) -> Result<String, String> {
+    let path = resource_dir.join(LOGIN_VIEW);
+    std::fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))
 }